@@ -1,6 +1,39 @@
 struct PolygonizationInfo {
     grid_size: vec3<f32>,
     grid_location: vec3<f32>,
+    // amount of cells inside of the grid box
+    cells: vec3<u32>,
+    // 1 on axes where the face of the grid box is stitched,
+    // one extra layer of cells outside of the box is polygonized there
+    apron_min: vec3<u32>,
+    apron_max: vec3<u32>,
+    // size of apron cells measured in cells of this grid, larger than 1 next to a coarser neighbour
+    apron_scale_min: vec3<u32>,
+    apron_scale_max: vec3<u32>,
+    // 1 if the surface is cut by the grid box, so the mesh is closed at the box faces
+    capped: u32,
+    // 1 if normals are taken from the field gradient at the vertex, 0 if from cell corners
+    gradient_normals: u32,
+    // newton iterations moving vertices onto the surface, 0 disables projection
+    projection_iterations: u32,
+    // projection stops once the field at the vertex is closer to 0 than this
+    projection_tolerance: f32,
+    // 1 if vertex and quad slots come from prefix sums over cells instead of atomic counters,
+    // so the output doesn't depend on the order invocations are executed in
+    deterministic: u32,
+    // optional attributes written after position and normal, VERTEX_* bits
+    vertex_attributes: u32,
+    // size of one texture repeat for generated uvs
+    uv_scale: f32,
+    // 1 if vertex colors carry material weights
+    materials: u32,
+    // samples of the ambient occlusion along the normal, 0 disables it
+    occlusion_samples: u32,
+    occlusion_distance: f32,
+    occlusion_strength: f32,
+    // size of apron cells across the edges of the box, measured in cells of this grid.
+    // 0 where quads along the edge belong to another grid, see `edge_index`
+    edge_scales: array<vec4<u32>, 3>,
 }
 
 struct DrawIndexedIndirect {
@@ -29,25 +62,185 @@ struct VertexInfo {
     intersections_bitmask: u32,
 }
 
+struct CellSample {
+    point: vec3<f32>,
+    normal: vec3<f32>,
+    intersections_count: u32,
+    intersections_bitmask: u32,
+}
+
 struct Atomics {
     vertex_count: atomic<u32>,
     quad_count: atomic<u32>,
 }
 
+// floats stored with ordered_bits, so atomicMin / atomicMax can be used on them
+struct Bounds {
+    min: array<atomic<u32>, 3>,
+    max: array<atomic<u32>, 3>,
+}
+
 @group(0) @binding(0) var<uniform> polygonization_info: PolygonizationInfo;
 @group(0) @binding(1) var<storage, read_write> vbo: array<f32>;
 @group(0) @binding(2) var<storage, read_write> ibo: array<u32>;
 @group(0) @binding(3) var<storage, read_write> vertices: array<VertexInfo>;
 @group(0) @binding(4) var<storage, read_write> atomics: Atomics;
+@group(0) @binding(5) var<storage, read_write> bounds: Bounds;
+// deterministic ordering only. for every cell: amount of vertices at [0, cells),
+// amount of quads at [cells + 1, 2 * cells + 1), turned into exclusive prefix sums
+// by scan_offsets which also writes the totals at [cells] and [2 * cells + 1]
+@group(0) @binding(6) var<storage, read_write> offsets: array<u32>;
+
+// only for bake_sdf, its layout has just the uniforms and this texture
+#ifdef SDF_R16FLOAT
+@group(0) @binding(7) var sdf_texture: texture_storage_3d<r16float, write>;
+#else
+@group(0) @binding(7) var sdf_texture: texture_storage_3d<r32float, write>;
+#endif
+
 @group(1) @binding(0) var<storage, read_write> indirect: DrawIndexedIndirect;
 
+// amount of cells polygonized, including apron
+fn grid_extent() -> vec3<u32> {
+    return polygonization_info.cells + polygonization_info.apron_min + polygonization_info.apron_max;
+}
+
+fn extent_cells_count() -> u32 {
+    let extent = grid_extent();
+    return extent.x * extent.y * extent.z;
+}
+
+fn vertex_offset_index(flat_cell_index: u32) -> u32 {
+    return flat_cell_index;
+}
+
+fn quad_offset_index(flat_cell_index: u32) -> u32 {
+    return extent_cells_count() + 1u + flat_cell_index;
+}
+
+// cell coordinates inside of the grid box, negative and >= cells for apron cells
+fn grid_cell(invocation_id: vec3<u32>) -> vec3<i32> {
+    return vec3<i32>(invocation_id) - vec3<i32>(polygonization_info.apron_min);
+}
+
+// which of the 4 edges along the axis the cell is next to, the other two axes follow
+// the axis cyclically and set bits 0 and 1 at their positive ends
+fn edge_index(cell: vec3<i32>, axis: u32) -> u32 {
+    let cells = vec3<i32>(polygonization_info.cells);
+    let first = (axis + 1u) % 3u;
+    let second = (axis + 2u) % 3u;
+    return select(0u, 1u, cell[first] >= cells[first]) | select(0u, 2u, cell[second] >= cells[second]);
+}
+
+fn apron_scale(cell: vec3<i32>) -> u32 {
+    let cells = vec3<i32>(polygonization_info.cells);
+    var scale = 1u;
+    var outside_axes = 0u;
+    var inside_axis = 0u;
+    for (var axis: u32 = 0u; axis < 3u; axis++) {
+        if (cell[axis] < 0) {
+            scale = max(scale, polygonization_info.apron_scale_min[axis]);
+            outside_axes += 1u;
+        } else if (cell[axis] >= cells[axis]) {
+            scale = max(scale, polygonization_info.apron_scale_max[axis]);
+            outside_axes += 1u;
+        } else {
+            inside_axis = axis;
+        }
+    }
+    // cells across an edge belong to the diagonal neighbour, which can have a different
+    // resolution than both face neighbours
+    if (outside_axes == 2u) {
+        let edge_scale = polygonization_info.edge_scales[inside_axis][edge_index(cell, inside_axis)];
+        if (edge_scale != 0u) {
+            scale = edge_scale;
+        }
+    }
+    return scale;
+}
+
+// first cell of the coarse neighbour's cell the apron cell is in
+fn coarse_cell(cell: vec3<i32>, scale: u32) -> vec3<i32> {
+    let cells = vec3<i32>(polygonization_info.cells);
+    let size = i32(scale);
+    var coarse = vec3<i32>(0, 0, 0);
+    for (var axis: u32 = 0u; axis < 3u; axis++) {
+        if (cell[axis] < 0) {
+            coarse[axis] = -size;
+        } else if (cell[axis] >= cells[axis]) {
+            coarse[axis] = cells[axis];
+        } else {
+            coarse[axis] = (cell[axis] / size) * size;
+        }
+    }
+    return coarse;
+}
+
+// quads are generated only for owned edges, edges on a stitched face are owned by this grid,
+// edges on the other faces belong to the neighbour or to nobody. edges on edges of the box
+// are owned only when the edge of the box is stitched
+fn owns_edge(cell: vec3<i32>, axis: u32) -> bool {
+    let cells = vec3<i32>(polygonization_info.cells);
+    var boundary_axes = 0u;
+    for (var other: u32 = 0u; other < 3u; other++) {
+        if (other == axis) {
+            if (cell[other] < 0 || cell[other] >= cells[other]) {
+                return false;
+            }
+            continue;
+        }
+        if (cell[other] < 0 || cell[other] > cells[other]) {
+            return false;
+        }
+        if (cell[other] == 0) {
+            if (polygonization_info.apron_min[other] == 0u) {
+                return false;
+            }
+            boundary_axes += 1u;
+        }
+        if (cell[other] == cells[other]) {
+            if (polygonization_info.apron_max[other] == 0u) {
+                return false;
+            }
+            boundary_axes += 1u;
+        }
+    }
+    if (boundary_axes == 2u) {
+        return polygonization_info.edge_scales[axis][edge_index(cell, axis)] != 0u;
+    }
+    return true;
+}
+
 fn flat_invocation_id(invocation_id: vec3<u32>, invocations_number: vec3<u32>) -> u32 {
     return invocation_id.x + invocation_id.y * invocations_number.x + invocation_id.z * invocations_number.x * invocations_number.y;
 }
 
+const VERTEX_UV: u32 = 1u;
+const VERTEX_TANGENT: u32 = 2u;
+const VERTEX_COLOR: u32 = 4u;
+
+fn has_vertex_attribute(attribute: u32) -> bool {
+    return (polygonization_info.vertex_attributes & attribute) != 0u;
+}
+
+// amount of floats per vertex, attributes are interleaved in the order of mesh attribute ids
+fn vertex_stride() -> u32 {
+    var stride = 6u;
+    if (has_vertex_attribute(VERTEX_UV)) {
+        stride += 2u;
+    }
+    if (has_vertex_attribute(VERTEX_TANGENT)) {
+        stride += 4u;
+    }
+    if (has_vertex_attribute(VERTEX_COLOR)) {
+        stride += 4u;
+    }
+    return stride;
+}
+
 // because vec3f has 16 bytes alighnment
 fn set_vbo_data(index: u32, value: vec3<f32>, normal: vec3<f32>) {
-    let offset = index * 6;
+    let offset = index * vertex_stride();
     vbo[offset] = value.x;
     vbo[offset + 1] = value.y;
     vbo[offset + 2] = value.z;
@@ -56,8 +249,89 @@ fn set_vbo_data(index: u32, value: vec3<f32>, normal: vec3<f32>) {
     vbo[offset + 5] = normal.z;
 }
 
+// maps float to u32 in a way that keeps the order of values
+fn ordered_bits(value: f32) -> u32 {
+    let bits = bitcast<u32>(value);
+    if ((bits & 0x80000000u) != 0u) {
+        return ~bits;
+    }
+    return bits | 0x80000000u;
+}
+
+fn extend_bounds(point: vec3<f32>) {
+    for (var i: u32 = 0u; i < 3u; i++) {
+        let bits = ordered_bits(point[i]);
+        atomicMin(&bounds.min[i], bits);
+        atomicMax(&bounds.max[i], bits);
+    }
+}
+
+fn set_vbo_uv(index: u32, uv: vec2<f32>) {
+    let offset = index * vertex_stride() + 6u;
+    vbo[offset] = uv.x;
+    vbo[offset + 1] = uv.y;
+}
+
+// tangent always follows uv
+fn set_vbo_tangent(index: u32, tangent: vec4<f32>) {
+    let offset = index * vertex_stride() + 8u;
+    vbo[offset] = tangent.x;
+    vbo[offset + 1] = tangent.y;
+    vbo[offset + 2] = tangent.z;
+    vbo[offset + 3] = tangent.w;
+}
+
+fn set_vbo_color(index: u32, color: vec4<f32>) {
+    var offset = index * vertex_stride() + 6u;
+    if (has_vertex_attribute(VERTEX_UV)) {
+        offset += 2u;
+    }
+    if (has_vertex_attribute(VERTEX_TANGENT)) {
+        offset += 4u;
+    }
+    vbo[offset] = color.x;
+    vbo[offset + 1] = color.y;
+    vbo[offset + 2] = color.z;
+    vbo[offset + 3] = color.w;
+}
+
+// box projection, the plane is picked by the axis closest to the normal.
+// u is flipped on the negative side so textures are not mirrored
+fn box_projection_uv(point: vec3<f32>, normal: vec3<f32>) -> vec2<f32> {
+    let p = point / polygonization_info.uv_scale;
+    let n = abs(normal);
+    if (n.x >= n.y && n.x >= n.z) {
+        return vec2<f32>(select(p.z, -p.z, normal.x > 0.0), -p.y);
+    }
+    if (n.y >= n.z) {
+        return vec2<f32>(select(-p.x, p.x, normal.y > 0.0), p.z);
+    }
+    return vec2<f32>(select(-p.x, p.x, normal.z > 0.0), -p.y);
+}
+
+// directions of increasing u and v of box_projection_uv, tangent is made orthogonal to
+// the normal and w keeps the handedness, bitangent = cross(normal, tangent.xyz) * tangent.w
+fn box_projection_tangent(normal: vec3<f32>) -> vec4<f32> {
+    let n = abs(normal);
+    var u_direction: vec3<f32>;
+    var v_direction: vec3<f32>;
+    if (n.x >= n.y && n.x >= n.z) {
+        u_direction = vec3<f32>(0.0, 0.0, select(1.0, -1.0, normal.x > 0.0));
+        v_direction = vec3<f32>(0.0, -1.0, 0.0);
+    } else if (n.y >= n.z) {
+        u_direction = vec3<f32>(select(-1.0, 1.0, normal.y > 0.0), 0.0, 0.0);
+        v_direction = vec3<f32>(0.0, 0.0, 1.0);
+    } else {
+        u_direction = vec3<f32>(select(-1.0, 1.0, normal.z > 0.0), 0.0, 0.0);
+        v_direction = vec3<f32>(0.0, -1.0, 0.0);
+    }
+    let tangent = normalize(u_direction - normal * dot(normal, u_direction));
+    let handedness = select(-1.0, 1.0, dot(cross(normal, tangent), v_direction) >= 0.0);
+    return vec4<f32>(tangent, handedness);
+}
+
 fn get_vbo_data(index: u32) -> array<vec3<f32>, 2>{
-    let offset = index * 6;
+    let offset = index * vertex_stride();
     return array<vec3<f32>, 2>(
         vec3<f32>(vbo[offset], vbo[offset + 1], vbo[offset + 2]),
         vec3<f32>(vbo[offset + 3], vbo[offset + 4], vbo[offset + 5]),
@@ -77,6 +351,7 @@ fn cube_vertices(vortex_size: vec3<f32>, vortex_origin: vec3<f32>) -> array<vec3
     );
 }
 
+// copied to `sdf` in src/field.rs for cpu queries, both have to be changed together
 fn sdf(x: vec3<f32>) -> f32 {
     let torus_radius = 3.f;
     let tube_radius = 2.f;
@@ -86,16 +361,59 @@ fn sdf(x: vec3<f32>) -> f32 {
     return length(q) - (tube_radius);
 }
 
+// copied to `sdf_material` in src/field.rs, both have to be changed together.
+// weights of up to four material layers at the point, they don't have to be normalized.
+// grass on top of the torus, dirt at the bottom and rock in between
+fn sdf_material(x: vec3<f32>) -> vec4<f32> {
+    let grass = smoothstep(0.5, 1.5, x.z);
+    let dirt = smoothstep(0.5, 1.5, -x.z);
+    let rock = 1.0 - grass - dirt;
+    return vec4<f32>(rock, dirt, grass, 0.0);
+}
+
+// 1 for open surfaces, lower where the field along the normal is closer than the distance
+// travelled, so something else is nearby. every next sample weighs half of the previous one
+fn ambient_occlusion(point: vec3<f32>, normal: vec3<f32>) -> f32 {
+    var occlusion = 0.0;
+    var weight = 1.0;
+    var total_weight = 0.0;
+    for (var i = 1u; i <= polygonization_info.occlusion_samples; i++) {
+        let distance = polygonization_info.occlusion_distance * f32(i);
+        let field_distance = field(point + normal * distance);
+        occlusion += weight * clamp((distance - field_distance) / distance, 0.0, 1.0);
+        total_weight += weight;
+        weight *= 0.5;
+    }
+    return clamp(1.0 - polygonization_info.occlusion_strength * occlusion / total_weight, 0.0, 1.0);
+}
+
+// box is shrunk by half a cell, so samples on the faces of the grid box are always outside
+fn grid_box_sdf(x: vec3<f32>) -> f32 {
+    let vortex_size = polygonization_info.grid_size / vec3<f32>(polygonization_info.cells);
+    let half_extents = (polygonization_info.grid_size - vortex_size) / 2.0;
+    let q = abs(x - polygonization_info.grid_location) - half_extents;
+    return length(max(q, vec3<f32>(0.0))) + min(max(q.x, max(q.y, q.z)), 0.0);
+}
+
+// the field which is polygonized
+fn field(x: vec3<f32>) -> f32 {
+    let value = sdf(x);
+    if (polygonization_info.capped == 0u) {
+        return value;
+    }
+    return max(value, grid_box_sdf(x));
+}
+
 fn sdfs(vertices: array<vec3<f32>, 8>) -> array<f32, 8> {
     return array<f32, 8>(
-        sdf(vertices[0]),
-        sdf(vertices[1]),
-        sdf(vertices[2]),
-        sdf(vertices[3]),
-        sdf(vertices[4]),
-        sdf(vertices[5]),
-        sdf(vertices[6]),
-        sdf(vertices[7]),
+        field(vertices[0]),
+        field(vertices[1]),
+        field(vertices[2]),
+        field(vertices[3]),
+        field(vertices[4]),
+        field(vertices[5]),
+        field(vertices[6]),
+        field(vertices[7]),
     );
 }
 
@@ -106,6 +424,38 @@ fn normal(sdfs: array<f32, 8>) -> vec3<f32> {
     return normalize(vec3<f32>(dx, dy, dz));
 }
 
+// central differences, epsilon is a small fraction of the cell
+fn field_gradient(x: vec3<f32>) -> vec3<f32> {
+    let vortex_size = polygonization_info.grid_size / vec3<f32>(polygonization_info.cells);
+    let epsilon = 0.01 * min(vortex_size.x, min(vortex_size.y, vortex_size.z));
+    let dx = vec3<f32>(epsilon, 0.0, 0.0);
+    let dy = vec3<f32>(0.0, epsilon, 0.0);
+    let dz = vec3<f32>(0.0, 0.0, epsilon);
+    return vec3<f32>(
+        field(x + dx) - field(x - dx),
+        field(x + dy) - field(x - dy),
+        field(x + dz) - field(x - dz),
+    ) / (2.0 * epsilon);
+}
+
+// vertex stays inside of its cell, otherwise quads could fold over
+fn project_to_surface(point: vec3<f32>, vortex_size: vec3<f32>, vortex_origin: vec3<f32>) -> vec3<f32> {
+    var projected = point;
+    for (var i: u32 = 0u; i < polygonization_info.projection_iterations; i++) {
+        let value = field(projected);
+        if (abs(value) < polygonization_info.projection_tolerance) {
+            break;
+        }
+        let gradient = field_gradient(projected);
+        let gradient_length_squared = dot(gradient, gradient);
+        if (gradient_length_squared == 0.0) {
+            break;
+        }
+        projected = clamp(projected - gradient * (value / gradient_length_squared), vortex_origin, vortex_origin + vortex_size);
+    }
+    return projected;
+}
+
 fn edge_bitmask(index: u32) -> u32 {
     return 1u << index;
 }
@@ -134,8 +484,8 @@ fn write_quad_to_ibo(index: u32, point0: u32, point1: u32, point2: u32, point3:
     }
 }
 
-@compute @workgroup_size(8, 8, 8)
-fn find_vertices(@builtin(global_invocation_id) invocation_id: vec3<u32>, @builtin(num_workgroups) num_workgroups: vec3<u32>) {
+// position and normal are skipped when only intersections are needed
+fn sample_cell(vortex_size: vec3<f32>, vortex_origin: vec3<f32>, with_point: bool) -> CellSample {
     var edges = array<vec2<u32>, 12>(
         vec2<u32>(0, 1),
         vec2<u32>(0, 2),
@@ -151,9 +501,6 @@ fn find_vertices(@builtin(global_invocation_id) invocation_id: vec3<u32>, @built
         vec2<u32>(6, 7)
     );
 
-    let invocations_number = num_workgroups * vec3<u32>(8, 8, 8);
-    let vortex_size = polygonization_info.grid_size / vec3<f32>(invocations_number);
-    let vortex_origin = (polygonization_info.grid_location - (polygonization_info.grid_size / 2.0)) + (vec3<f32>(invocation_id) * vortex_size);
     var local_vertices = cube_vertices(vortex_size, vortex_origin);
     var sdfs = sdfs(local_vertices);
 
@@ -180,18 +527,86 @@ fn find_vertices(@builtin(global_invocation_id) invocation_id: vec3<u32>, @built
             }
         }
     }
-    var index: u32 = 0;
-    if intersections_count > 0 {
-        let point = sum / f32(intersections_count);
-        let normal = normal(sdfs);
-        index = atomicAdd(&atomics.vertex_count, 1u);
-        set_vbo_data(index, point, normal);
+
+    var sample = CellSample(vec3<f32>(0.0), vec3<f32>(0.0), intersections_count, intersections_bitmask);
+    if (with_point && intersections_count > 0) {
+        sample.point = project_to_surface(sum / f32(intersections_count), vortex_size, vortex_origin);
+        sample.normal = normal(sdfs);
+    }
+    return sample;
+}
+
+@compute @workgroup_size(8, 8, 8)
+fn find_vertices(@builtin(global_invocation_id) invocation_id: vec3<u32>, @builtin(num_workgroups) num_workgroups: vec3<u32>) {
+    let invocations_number = grid_extent();
+    if (any(invocation_id >= invocations_number)) {
+        return;
+    }
+    let cell = grid_cell(invocation_id);
+    let vortex_size = polygonization_info.grid_size / vec3<f32>(polygonization_info.cells);
+    let grid_min = polygonization_info.grid_location - (polygonization_info.grid_size / 2.0);
+    var sample = sample_cell(vortex_size, grid_min + (vec3<f32>(cell) * vortex_size), true);
+
+    // apron cells next to a coarser neighbour take the vertex of the neighbour's cell, so both
+    // grids put the same vertices along the face. connectivity still comes from this grid
+    let scale = apron_scale(cell);
+    if (scale > 1u) {
+        let coarse_origin = grid_min + (vec3<f32>(coarse_cell(cell, scale)) * vortex_size);
+        let coarse = sample_cell(vortex_size * f32(scale), coarse_origin, true);
+        if (coarse.intersections_count > 0u) {
+            sample.point = coarse.point;
+            sample.normal = coarse.normal;
+        }
+    }
+
+    if sample.intersections_count > 0 {
+        if (polygonization_info.gradient_normals != 0u) {
+            sample.normal = normalize(field_gradient(sample.point));
+        }
         let flat_index = flat_invocation_id(invocation_id, invocations_number);
-        vertices[index] = VertexInfo(flat_index, intersections_bitmask);
+        var index: u32;
+        if (polygonization_info.deterministic != 0u) {
+            index = offsets[vertex_offset_index(flat_index)];
+        } else {
+            index = atomicAdd(&atomics.vertex_count, 1u);
+        }
+        set_vbo_data(index, sample.point, sample.normal);
+        if (has_vertex_attribute(VERTEX_UV)) {
+            set_vbo_uv(index, box_projection_uv(sample.point, sample.normal));
+        }
+        if (has_vertex_attribute(VERTEX_TANGENT)) {
+            set_vbo_tangent(index, box_projection_tangent(sample.normal));
+        }
+        if (has_vertex_attribute(VERTEX_COLOR)) {
+            var color = vec4<f32>(1.0);
+            if (polygonization_info.materials != 0u) {
+                let weights = max(sdf_material(sample.point), vec4<f32>(0.0));
+                color = weights / max(dot(weights, vec4<f32>(1.0)), 1e-6);
+            }
+            if (polygonization_info.occlusion_samples > 0u) {
+                let occlusion = ambient_occlusion(sample.point, sample.normal);
+                // weights are all scaled, alpha of a plain vertex color is left alone
+                if (polygonization_info.materials != 0u) {
+                    color *= occlusion;
+                } else {
+                    color = vec4<f32>(vec3<f32>(occlusion), 1.0);
+                }
+            }
+            set_vbo_color(index, color);
+        }
+        extend_bounds(sample.point);
+        vertices[index] = VertexInfo(flat_index, sample.intersections_bitmask);
     }
 }
 
 fn search_cell_index(target_flat_cell_index: u32) -> i32 {
+    if (polygonization_info.deterministic != 0u) {
+        let offset = offsets[vertex_offset_index(target_flat_cell_index)];
+        if (offsets[vertex_offset_index(target_flat_cell_index + 1u)] == offset) {
+            return -1;
+        }
+        return i32(offset);
+    }
     for (var i: u32 = 0u; i < atomics.vertex_count; i++) {
         if (vertices[i].flat_cell_index == target_flat_cell_index) {
             return i32(i);
@@ -200,56 +615,162 @@ fn search_cell_index(target_flat_cell_index: u32) -> i32 {
     return -1;
 }
 
+// quads of one cell take consecutive slots starting at the cell's offset
+fn allocate_quad(next_quad: ptr<function, u32>) -> u32 {
+    if (polygonization_info.deterministic != 0u) {
+        let quad_index = *next_quad;
+        *next_quad += 1u;
+        return quad_index;
+    }
+    return atomicAdd(&atomics.quad_count, 1u);
+}
+
 @compute @workgroup_size(8, 8, 8)
 fn connect_vertices(@builtin(global_invocation_id) invocation_id: vec3<u32>, @builtin(num_workgroups) num_workgroups: vec3<u32>) {
-    let invocations_number = num_workgroups * vec3<u32>(8, 8, 8);
+    let invocations_number = grid_extent();
+    if (any(invocation_id >= invocations_number)) {
+        return;
+    }
+    let cell = grid_cell(invocation_id);
     let id = flat_invocation_id(invocation_id, invocations_number);
     let id0 = search_cell_index(id);
     if (id0 == -1) {
         return;
     }
     let cell1 = vertices[id0];
+    var next_quad = 0u;
+    if (polygonization_info.deterministic != 0u) {
+        next_quad = offsets[quad_offset_index(id)];
+    }
     if ((cell1.intersections_bitmask & edge_bitmask(0u)) != 0u) {
-        if (invocation_id.y != 0 && invocation_id.z != 0) {
+        if (owns_edge(cell, 0u)) {
             let id1 = flat_invocation_id(invocation_id - vec3<u32>(0, 1, 0), invocations_number);
             let vbo_index_point1 = u32(search_cell_index(id1));
             let id2 = flat_invocation_id(invocation_id - vec3<u32>(0, 0, 1), invocations_number);
             let vbo_index_point2 = u32(search_cell_index(id2));
             let id3 = flat_invocation_id(invocation_id - vec3<u32>(0, 1, 1), invocations_number);
             let vbo_index_point3 = u32(search_cell_index(id3));
-            let quad_index = atomicAdd(&atomics.quad_count, 1u);
+            let quad_index = allocate_quad(&next_quad);
             let order = (cell1.intersections_bitmask & edge_bitmask(3u)) == 0u;
             write_quad_to_ibo(quad_index, u32(id0), vbo_index_point1, vbo_index_point2, vbo_index_point3, order);
         }
     }
     if ((cell1.intersections_bitmask & edge_bitmask(1u)) != 0u) {
-        if (invocation_id.x != 0 && invocation_id.z != 0) {
+        if (owns_edge(cell, 1u)) {
             let id1 = flat_invocation_id(invocation_id - vec3<u32>(1, 0, 0), invocations_number);
             let vbo_index_point1 = u32(search_cell_index(id1));
             let id2 = flat_invocation_id(invocation_id - vec3<u32>(0, 0, 1), invocations_number);
             let vbo_index_point2 = u32(search_cell_index(id2));
             let id3 = flat_invocation_id(invocation_id - vec3<u32>(1, 0, 1), invocations_number);
             let vbo_index_point3 = u32(search_cell_index(id3));
-            let quad_index = atomicAdd(&atomics.quad_count, 1u);
+            let quad_index = allocate_quad(&next_quad);
             let order = (cell1.intersections_bitmask & edge_bitmask(4u)) != 0u;
             write_quad_to_ibo(quad_index, u32(id0), vbo_index_point1, vbo_index_point2, vbo_index_point3, order);
         }
     }
     if ((cell1.intersections_bitmask & edge_bitmask(2u)) != 0u) {
-        if (invocation_id.x != 0 && invocation_id.y != 0) {
+        if (owns_edge(cell, 2u)) {
             let id1 = flat_invocation_id(invocation_id - vec3<u32>(1, 0, 0), invocations_number);
             let vbo_index_point1 = u32(search_cell_index(id1));
             let id2 = flat_invocation_id(invocation_id - vec3<u32>(0, 1, 0), invocations_number);
             let vbo_index_point2 = u32(search_cell_index(id2));
             let id3 = flat_invocation_id(invocation_id - vec3<u32>(1, 1, 0), invocations_number);
             let vbo_index_point3 = u32(search_cell_index(id3));
-            let quad_index = atomicAdd(&atomics.quad_count, 1u);
+            let quad_index = allocate_quad(&next_quad);
             let order = (cell1.intersections_bitmask & edge_bitmask(5u)) == 0u;
             write_quad_to_ibo(quad_index, u32(id0), vbo_index_point1, vbo_index_point2, vbo_index_point3, order);
         }
     }
 }
 
+// one texel per cell of the grid box, the field is evaluated at the center of the cell
+@compute @workgroup_size(8, 8, 8)
+fn bake_sdf(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    if (any(invocation_id >= polygonization_info.cells)) {
+        return;
+    }
+    let vortex_size = polygonization_info.grid_size / vec3<f32>(polygonization_info.cells);
+    let grid_min = polygonization_info.grid_location - (polygonization_info.grid_size / 2.0);
+    let point = grid_min + (vec3<f32>(invocation_id) + 0.5) * vortex_size;
+    textureStore(sdf_texture, invocation_id, vec4<f32>(field(point), 0.0, 0.0, 0.0));
+}
+
+// deterministic ordering only, writes amount of vertices and owned quads of every cell
+@compute @workgroup_size(8, 8, 8)
+fn count_cells(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let invocations_number = grid_extent();
+    if (any(invocation_id >= invocations_number)) {
+        return;
+    }
+    let cell = grid_cell(invocation_id);
+    let vortex_size = polygonization_info.grid_size / vec3<f32>(polygonization_info.cells);
+    let grid_min = polygonization_info.grid_location - (polygonization_info.grid_size / 2.0);
+    let sample = sample_cell(vortex_size, grid_min + (vec3<f32>(cell) * vortex_size), false);
+
+    var quad_count = 0u;
+    for (var axis: u32 = 0u; axis < 3u; axis++) {
+        if ((sample.intersections_bitmask & edge_bitmask(axis)) != 0u && owns_edge(cell, axis)) {
+            quad_count += 1u;
+        }
+    }
+    let id = flat_invocation_id(invocation_id, invocations_number);
+    offsets[vertex_offset_index(id)] = select(0u, 1u, sample.intersections_count > 0u);
+    offsets[quad_offset_index(id)] = quad_count;
+}
+
+const SCAN_WORKGROUP_SIZE: u32 = 256u;
+
+var<workgroup> scan_partials: array<u32, SCAN_WORKGROUP_SIZE>;
+
+// exclusive prefix sum over `count` values of offsets starting at `start`, returns the total.
+// every invocation scans its own consecutive range, sums of the ranges are scanned in between
+fn scan_range(local_index: u32, start: u32, count: u32) -> u32 {
+    let per_invocation = (count + SCAN_WORKGROUP_SIZE - 1u) / SCAN_WORKGROUP_SIZE;
+    let begin = min(local_index * per_invocation, count);
+    let end = min(begin + per_invocation, count);
+
+    var range_sum = 0u;
+    for (var i = begin; i < end; i++) {
+        range_sum += offsets[start + i];
+    }
+    scan_partials[local_index] = range_sum;
+    workgroupBarrier();
+
+    for (var stride = 1u; stride < SCAN_WORKGROUP_SIZE; stride *= 2u) {
+        var previous = 0u;
+        if (local_index >= stride) {
+            previous = scan_partials[local_index - stride];
+        }
+        workgroupBarrier();
+        scan_partials[local_index] += previous;
+        workgroupBarrier();
+    }
+
+    var running = scan_partials[local_index] - range_sum;
+    for (var i = begin; i < end; i++) {
+        let value = offsets[start + i];
+        offsets[start + i] = running;
+        running += value;
+    }
+    let total = scan_partials[SCAN_WORKGROUP_SIZE - 1u];
+    workgroupBarrier();
+    return total;
+}
+
+// deterministic ordering only, dispatched as a single workgroup
+@compute @workgroup_size(256, 1, 1)
+fn scan_offsets(@builtin(local_invocation_index) local_index: u32) {
+    let cells = extent_cells_count();
+    let vertex_count = scan_range(local_index, vertex_offset_index(0u), cells);
+    let quad_count = scan_range(local_index, quad_offset_index(0u), cells);
+    if (local_index == 0u) {
+        offsets[vertex_offset_index(cells)] = vertex_count;
+        offsets[quad_offset_index(cells)] = quad_count;
+        atomicStore(&atomics.vertex_count, vertex_count);
+        atomicStore(&atomics.quad_count, quad_count);
+    }
+}
+
 @compute @workgroup_size(1, 1, 1)
 fn prepare_indirect_buffer() {
     indirect.first_instance = 0u;
@@ -0,0 +1,72 @@
+#import bevy_pbr::{
+    pbr_fragment::pbr_input_from_standard_material,
+    pbr_functions::alpha_discard,
+}
+
+#ifdef PREPASS_PIPELINE
+#import bevy_pbr::{
+    prepass_io::{VertexOutput, FragmentOutput},
+    pbr_deferred_functions::deferred_output,
+}
+#else
+#import bevy_pbr::{
+    forward_io::{VertexOutput, FragmentOutput},
+    pbr_functions::{apply_pbr_lighting, main_pass_post_lighting_processing},
+}
+#endif
+
+struct IsosurfaceMaterialLayers {
+    colors: array<vec4<f32>, 4>,
+}
+
+@group(2) @binding(100) var<uniform> layers: IsosurfaceMaterialLayers;
+@group(2) @binding(101) var texture_0: texture_2d<f32>;
+@group(2) @binding(102) var sampler_0: sampler;
+@group(2) @binding(103) var texture_1: texture_2d<f32>;
+@group(2) @binding(104) var sampler_1: sampler;
+@group(2) @binding(105) var texture_2: texture_2d<f32>;
+@group(2) @binding(106) var sampler_2: sampler;
+@group(2) @binding(107) var texture_3: texture_2d<f32>;
+@group(2) @binding(108) var sampler_3: sampler;
+
+// weights are not normalized here, scaled down weights darken the surface
+fn blend_layers(in: VertexOutput) -> vec4<f32> {
+#ifdef VERTEX_COLORS
+    let weights = in.color;
+#else
+    let weights = vec4<f32>(1.0, 0.0, 0.0, 0.0);
+#endif
+    var colors = layers.colors;
+#ifdef VERTEX_UVS_A
+    colors[0] *= textureSample(texture_0, sampler_0, in.uv);
+    colors[1] *= textureSample(texture_1, sampler_1, in.uv);
+    colors[2] *= textureSample(texture_2, sampler_2, in.uv);
+    colors[3] *= textureSample(texture_3, sampler_3, in.uv);
+#endif
+    let color = colors[0] * weights.x + colors[1] * weights.y
+        + colors[2] * weights.z + colors[3] * weights.w;
+    // alpha is an average of the layers, it shouldn't get darker with the color
+    let alpha = color.a / max(dot(weights, vec4<f32>(1.0)), 1e-6);
+    return vec4<f32>(color.rgb, alpha);
+}
+
+@fragment
+fn fragment(
+    in: VertexOutput,
+    @builtin(front_facing) is_front: bool,
+) -> FragmentOutput {
+    var pbr_input = pbr_input_from_standard_material(in, is_front);
+    // base color of the standard material is multiplied by the weights, layers replace it
+    pbr_input.material.base_color = blend_layers(in);
+    pbr_input.material.base_color = alpha_discard(pbr_input.material, pbr_input.material.base_color);
+
+#ifdef PREPASS_PIPELINE
+    let out = deferred_output(in, pbr_input);
+#else
+    var out: FragmentOutput;
+    out.color = apply_pbr_lighting(pbr_input);
+    out.color = main_pass_post_lighting_processing(pbr_input, out.color);
+#endif
+
+    return out;
+}
@@ -1,4 +1,4 @@
-use bevy::{pbr::PbrPlugin, prelude::*, render::view::NoFrustumCulling};
+use bevy::{pbr::PbrPlugin, prelude::*};
 
 use bevy_ugr::{Isosurface, IsosurfaceHandle, IsosurfacePlugin};
 
@@ -39,6 +39,7 @@ pub fn setup(
         grid_size: Vec3::new(7.0, 7.0, 7.0),
         grid_origin: Vec3::new(0.0, 0.0, 0.0),
         grid_density: UVec3::new(1, 1, 1),
+        tight_bounds: true,
         ..default()
     });
     info!(
@@ -54,7 +55,6 @@ pub fn setup(
         })),
         Transform::from_xyz(0.0, 3.0, 0.0),
         Visibility::Visible,
-        NoFrustumCulling,
     ));
 }
 
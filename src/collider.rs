@@ -0,0 +1,222 @@
+use bevy::{
+    prelude::*,
+    render::mesh::VertexAttributeValues,
+    utils::{HashMap, HashSet},
+};
+
+use crate::{
+    volume::{copy_volume_component, update_volume_chunks},
+    ChangedIsosurfaces, Isosurface,
+};
+
+pub struct IsosurfaceColliderPlugin;
+
+impl Plugin for IsosurfaceColliderPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            PostUpdate,
+            (
+                copy_volume_component::<IsosurfaceColliderSettings>.after(update_volume_chunks),
+                update_isosurface_colliders,
+            )
+                .chain(),
+        )
+        .register_type::<IsosurfaceColliderSettings>();
+    }
+}
+
+// opt-in, `IsosurfaceCollider` is inserted next to it and regenerated whenever the
+// isosurface changes. meshes are polygonized again on the cpu for this, the gpu ones
+// never reach the main world. settings on `IsosurfaceVolume` are copied to its chunks,
+// chunks are regenerated one by one, an edit inside of a chunk redoes the whole chunk
+#[derive(Component, Clone, Copy, Debug, Default, Reflect, PartialEq)]
+#[reflect(Component, Default)]
+pub struct IsosurfaceColliderSettings {
+    // size of convex parts measured in cells, no decomposition if `None`
+    pub convex_part_cells: Option<u32>,
+}
+
+// collider data in local space of the isosurface, for any physics crate
+#[derive(Component, Clone, Debug, Default)]
+pub struct IsosurfaceCollider {
+    // without duplicates, triangles sharing a position share the vertex
+    pub vertices: Vec<Vec3>,
+    pub indices: Vec<[u32; 3]>,
+    // points of every part, their convex hulls approximate the solid. parts are blocks of
+    // the grid, so hulls overestimate concave shapes inside of a block. blocks the surface
+    // goes through take its vertices and their corners inside of the solid, blocks inside
+    // of the solid are whole boxes
+    pub convex_parts: Vec<Vec<Vec3>>,
+}
+
+impl IsosurfaceCollider {
+    pub fn new(isosurface: &Isosurface, settings: &IsosurfaceColliderSettings) -> Self {
+        let (vertices, indices) = welded_triangles(isosurface);
+        let convex_parts = settings
+            .convex_part_cells
+            .map(|part_cells| convex_parts(isosurface, part_cells, &vertices, &indices))
+            .unwrap_or_default();
+
+        Self {
+            vertices,
+            indices,
+            convex_parts,
+        }
+    }
+}
+
+// cpu polygonization with vertices welded by their exact position, degenerate triangles
+// are dropped
+pub(crate) fn welded_triangles(isosurface: &Isosurface) -> (Vec<Vec3>, Vec<[u32; 3]>) {
+    let mesh = isosurface.polygonize();
+    let Some(VertexAttributeValues::Float32x3(positions)) =
+        mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+    else {
+        return (Vec::new(), Vec::new());
+    };
+
+    let mut vertices = Vec::new();
+    let mut welded = HashMap::new();
+    let remap: Vec<u32> = positions
+        .iter()
+        .map(|position| {
+            *welded.entry(position.map(f32::to_bits)).or_insert_with(|| {
+                vertices.push(Vec3::from_array(*position));
+                vertices.len() as u32 - 1
+            })
+        })
+        .collect();
+    let indices: Vec<[u32; 3]> = mesh
+        .indices()
+        .map(|indices| indices.iter().collect::<Vec<_>>())
+        .unwrap_or_default()
+        .chunks_exact(3)
+        .map(|triangle| [0, 1, 2].map(|i| remap[triangle[i]]))
+        .filter(|[a, b, c]| a != b && b != c && a != c)
+        .collect();
+    (vertices, indices)
+}
+
+// triangles go to the block of their centroid, so neighbouring parts overlap a little.
+// corners of the block inside of the solid are added to its part, vertices alone would give
+// a thin shell under the surface. blocks without triangles are filled when the field is
+// negative at all their corners
+fn convex_parts(
+    isosurface: &Isosurface,
+    part_cells: u32,
+    vertices: &[Vec3],
+    indices: &[[u32; 3]],
+) -> Vec<Vec<Vec3>> {
+    let part_size = isosurface.cell_size() * part_cells.max(1) as f32;
+    let grid_min = isosurface.grid_origin - isosurface.grid_size / 2.0;
+    let mut parts: HashMap<IVec3, HashSet<u32>> = HashMap::new();
+    for triangle in indices {
+        let centroid = triangle
+            .iter()
+            .map(|index| vertices[*index as usize])
+            .sum::<Vec3>()
+            / 3.0;
+        let block = ((centroid - grid_min) / part_size).floor().as_ivec3();
+        parts.entry(block).or_default().extend(triangle);
+    }
+    let part_points = |part: HashSet<u32>| -> Vec<Vec3> {
+        part.iter().map(|index| vertices[*index as usize]).collect()
+    };
+
+    let mut convex_parts = Vec::new();
+    let grid_max = grid_min + isosurface.grid_size;
+    let blocks = (isosurface.grid_size / part_size).ceil().as_ivec3();
+    for z in 0..blocks.z {
+        for y in 0..blocks.y {
+            for x in 0..blocks.x {
+                let block = IVec3::new(x, y, z);
+                let block_min = grid_min + block.as_vec3() * part_size;
+                let block_max = (block_min + part_size).min(grid_max);
+                let inside_corners: Vec<Vec3> = (0..8)
+                    .map(|corner| {
+                        Vec3::select(
+                            BVec3::new(corner & 1 != 0, corner & 2 != 0, corner & 4 != 0),
+                            block_max,
+                            block_min,
+                        )
+                    })
+                    .filter(|corner| isosurface.field(*corner) < 0.0)
+                    .collect();
+                match parts.remove(&block) {
+                    Some(part) => {
+                        let mut points = part_points(part);
+                        points.extend(inside_corners);
+                        convex_parts.push(points);
+                    }
+                    None if inside_corners.len() == 8 => convex_parts.push(inside_corners),
+                    None => {}
+                }
+            }
+        }
+    }
+    // triangles of stitching aprons are outside of the grid box
+    convex_parts.extend(parts.into_values().map(part_points));
+    convex_parts
+}
+
+fn update_isosurface_colliders(
+    mut commands: Commands,
+    mut changed: ChangedIsosurfaces<IsosurfaceColliderSettings>,
+) {
+    for (entity, _, isosurface, settings) in changed.read() {
+        commands
+            .entity(entity)
+            .insert(IsosurfaceCollider::new(isosurface, settings));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::IsosurfaceBoundary;
+
+    use super::*;
+
+    // no plane through the point separates it from the points, checked for a set of
+    // directions, which is enough for the boxy parts here
+    fn inside_hull(points: &[Vec3], point: Vec3) -> bool {
+        let mut directions = Vec::new();
+        for z in -2..=2 {
+            for y in -2..=2 {
+                for x in -2..=2 {
+                    if let Some(direction) = IVec3::new(x, y, z).as_vec3().try_normalize() {
+                        directions.push(direction);
+                    }
+                }
+            }
+        }
+        directions.iter().all(|direction| {
+            points
+                .iter()
+                .any(|corner| direction.dot(*corner - point) >= 0.0)
+        })
+    }
+
+    #[test]
+    fn point_under_flat_surface_is_inside_part() {
+        // the grid box cuts the tube, the cap on top is flat at z = 0.875
+        let isosurface = Isosurface {
+            grid_size: Vec3::new(12.0, 12.0, 2.0),
+            grid_density: UVec3::new(2, 2, 1),
+            boundary: IsosurfaceBoundary::Capped,
+            ..default()
+        };
+        let collider = IsosurfaceCollider::new(
+            &isosurface,
+            &IsosurfaceColliderSettings {
+                convex_part_cells: Some(2),
+            },
+        );
+
+        let point = Vec3::new(2.25, 0.75, 0.8);
+        assert!(isosurface.field(point) < 0.0);
+        assert!(collider
+            .convex_parts
+            .iter()
+            .any(|part| inside_hull(part, point)));
+    }
+}
@@ -0,0 +1,355 @@
+use bevy::{ecs::system::SystemParam, math::Affine3A, prelude::*, render::primitives::Aabb};
+
+use crate::{Isosurface, IsosurfaceHandle, IsosurfaceRayHit};
+
+// samples along a segment before the deepest one is refined
+const SEGMENT_SAMPLES: u32 = 8;
+const REFINE_ITERATIONS: u32 = 8;
+// steps of conservative advancement before a cast is considered to miss
+const MAX_CAST_STEPS: u32 = 256;
+
+// the closest surface to a shape
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IsosurfaceContact {
+    pub entity: Entity,
+    // on the surface, in world space
+    pub point: Vec3,
+    // points out of the surface, the shape is pushed out along it
+    pub normal: Dir3,
+    // how deep the shape is inside of the surface, negative is the gap between them
+    pub penetration: f32,
+}
+
+// shapes are tested against the field on the cpu, so meshes of isosurfaces are never needed.
+// like with raycasts only the surface inside of the grid box counts. the field keeps distances
+// only under uniform scale, non-uniform scale is approximated by its largest axis
+#[derive(SystemParam)]
+pub struct IsosurfaceCollisions<'w, 's> {
+    isosurfaces: Res<'w, Assets<Isosurface>>,
+    entities: Query<'w, 's, (Entity, &'static IsosurfaceHandle, &'static GlobalTransform)>,
+}
+
+impl IsosurfaceCollisions<'_, '_> {
+    // contacts of every isosurface entity within `max_distance` of the shape
+    pub fn sphere_contacts(
+        &self,
+        sphere: Sphere,
+        center: Vec3,
+        max_distance: f32,
+    ) -> Vec<IsosurfaceContact> {
+        self.contacts(max_distance, |isosurface, local| {
+            let center = local.inverse.transform_point3(center);
+            let radius = sphere.radius / local.scale;
+            let value = isosurface.field(center);
+            (center, value - radius)
+        })
+    }
+
+    // the capsule is along the y axis of the isometry, like `Capsule3d` is
+    pub fn capsule_contacts(
+        &self,
+        capsule: Capsule3d,
+        isometry: Isometry3d,
+        max_distance: f32,
+    ) -> Vec<IsosurfaceContact> {
+        self.contacts(max_distance, |isosurface, local| {
+            let (start, end) = local.capsule_segment(capsule, isometry);
+            let (point, value) = isosurface.segment_deepest_point(start, end);
+            (point, value - capsule.radius / local.scale)
+        })
+    }
+
+    pub fn box_contacts(
+        &self,
+        cuboid: Cuboid,
+        isometry: Isometry3d,
+        max_distance: f32,
+    ) -> Vec<IsosurfaceContact> {
+        self.contacts(max_distance, |isosurface, local| {
+            let center = local
+                .inverse
+                .transform_point3(Vec3::from(isometry.translation));
+            let rotation = local.rotation.inverse() * isometry.rotation;
+            isosurface.box_deepest_point(center, rotation, cuboid.half_size / local.scale)
+        })
+    }
+
+    // first hit of the capsule moved along the direction, closest of all isosurface entities.
+    // a capsule already touching a surface hits it at distance 0
+    pub fn cast_capsule(
+        &self,
+        capsule: Capsule3d,
+        isometry: Isometry3d,
+        direction: Dir3,
+        max_distance: f32,
+    ) -> Option<IsosurfaceRayHit> {
+        let mut closest: Option<IsosurfaceRayHit> = None;
+        for (entity, isosurface, local) in self.local_isosurfaces() {
+            let max_distance = closest.map_or(max_distance, |hit| hit.distance);
+            let (start, end) = local.capsule_segment(capsule, isometry);
+            let local_direction = local.rotation.inverse() * *direction;
+            let radius = capsule.radius / local.scale;
+            let Some((local_distance, point, value)) = isosurface.cast_segment(
+                start,
+                end,
+                radius,
+                local_direction,
+                max_distance / local.scale,
+            ) else {
+                continue;
+            };
+            let Some((point, normal)) = local.surface_point(isosurface, point, value) else {
+                continue;
+            };
+            closest = Some(IsosurfaceRayHit {
+                entity,
+                point,
+                normal,
+                distance: local_distance * local.scale,
+            });
+        }
+        closest
+    }
+
+    fn local_isosurfaces(&self) -> Vec<(Entity, &Isosurface, LocalSpace)> {
+        self.entities
+            .iter()
+            .filter_map(|(entity, isosurface_handle, transform)| {
+                let isosurface = self.isosurfaces.get(&isosurface_handle.0)?;
+                Some((entity, isosurface, LocalSpace::new(transform)))
+            })
+            .collect()
+    }
+
+    // `deepest` returns the point of the shape deepest in the field in local space and
+    // the field value there, reduced by the radius of the shape
+    fn contacts(
+        &self,
+        max_distance: f32,
+        deepest: impl Fn(&Isosurface, &LocalSpace) -> (Vec3, f32),
+    ) -> Vec<IsosurfaceContact> {
+        let mut contacts = Vec::new();
+        for (entity, isosurface, local) in self.local_isosurfaces() {
+            let (point, value) = deepest(isosurface, &local);
+            if value * local.scale > max_distance {
+                continue;
+            }
+            let Some((point, normal)) =
+                local.surface_point(isosurface, point, isosurface.field(point))
+            else {
+                continue;
+            };
+            contacts.push(IsosurfaceContact {
+                entity,
+                point,
+                normal,
+                penetration: -value * local.scale,
+            });
+        }
+        contacts
+    }
+}
+
+struct LocalSpace {
+    affine: Affine3A,
+    inverse: Affine3A,
+    rotation: Quat,
+    scale: f32,
+}
+
+impl LocalSpace {
+    fn new(transform: &GlobalTransform) -> Self {
+        let (scale, rotation, _) = transform.to_scale_rotation_translation();
+        let affine = transform.affine();
+        Self {
+            affine,
+            inverse: affine.inverse(),
+            rotation,
+            scale: scale.abs().max_element(),
+        }
+    }
+
+    fn capsule_segment(&self, capsule: Capsule3d, isometry: Isometry3d) -> (Vec3, Vec3) {
+        let half_segment = isometry.rotation * Vec3::Y * capsule.half_length;
+        let center = Vec3::from(isometry.translation);
+        (
+            self.inverse.transform_point3(center - half_segment),
+            self.inverse.transform_point3(center + half_segment),
+        )
+    }
+
+    // the closest point of the surface to a point in local space where the field is `value`.
+    // surfaces outside of the grid box aren't polygonized, so they don't count
+    fn surface_point(
+        &self,
+        isosurface: &Isosurface,
+        point: Vec3,
+        value: f32,
+    ) -> Option<(Vec3, Dir3)> {
+        let normal = isosurface.field_gradient(point).try_normalize()?;
+        let surface_point = point - normal * value;
+        if !aabb_contains(&isosurface.grid_aabb(), surface_point) {
+            return None;
+        }
+        Some((
+            self.affine.transform_point3(surface_point),
+            Dir3::new(self.rotation * normal).ok()?,
+        ))
+    }
+}
+
+fn aabb_contains(aabb: &Aabb, point: Vec3) -> bool {
+    let point = Vec3A::from(point);
+    point.cmpge(aabb.min()).all() && point.cmple(aabb.max()).all()
+}
+
+impl Isosurface {
+    // the field along a segment can have several minimums, the best sample is refined
+    pub(crate) fn segment_deepest_point(&self, start: Vec3, end: Vec3) -> (Vec3, f32) {
+        let value_at = |t: f32| self.field(start.lerp(end, t));
+        let step = 1.0 / SEGMENT_SAMPLES as f32;
+        let best = (0..=SEGMENT_SAMPLES)
+            .map(|i| i as f32 * step)
+            .min_by(|a, b| value_at(*a).total_cmp(&value_at(*b)))
+            .unwrap_or_default();
+
+        // ternary search around the best sample
+        let (mut low, mut high) = ((best - step).max(0.0), (best + step).min(1.0));
+        for _ in 0..REFINE_ITERATIONS {
+            let a = low + (high - low) / 3.0;
+            let b = high - (high - low) / 3.0;
+            if value_at(a) < value_at(b) {
+                high = b;
+            } else {
+                low = a;
+            }
+        }
+        let t = (low + high) / 2.0;
+        let point = start.lerp(end, t);
+        (point, self.field(point))
+    }
+
+    // the best point of a 3x3x3 lattice over the box goes down the gradient,
+    // staying inside of the box
+    fn box_deepest_point(&self, center: Vec3, rotation: Quat, half_size: Vec3) -> (Vec3, f32) {
+        let to_world = |box_point: Vec3| center + rotation * box_point;
+        let mut best_point = Vec3::ZERO;
+        let mut best_value = f32::INFINITY;
+        for z in -1..=1 {
+            for y in -1..=1 {
+                for x in -1..=1 {
+                    let box_point = IVec3::new(x, y, z).as_vec3() * half_size;
+                    let value = self.field(to_world(box_point));
+                    if value < best_value {
+                        best_point = box_point;
+                        best_value = value;
+                    }
+                }
+            }
+        }
+
+        for _ in 0..REFINE_ITERATIONS {
+            let gradient = rotation.inverse() * self.field_gradient(to_world(best_point));
+            let box_point = (best_point - gradient * best_value.abs()).clamp(-half_size, half_size);
+            let value = self.field(to_world(box_point));
+            if value >= best_value {
+                break;
+            }
+            best_point = box_point;
+            best_value = value;
+        }
+        (to_world(best_point), best_value)
+    }
+
+    // conservative advancement, the segment moves by a lower bound of its gap to the surface
+    // so it can't pass through it. when the bound is too loose to tell whether the surface is
+    // touched, the samples are made denser instead of moving.
+    // returns the distance, the deepest point of the segment there and the field at it
+    fn cast_segment(
+        &self,
+        start: Vec3,
+        end: Vec3,
+        radius: f32,
+        direction: Vec3,
+        max_distance: f32,
+    ) -> Option<(f32, Vec3, f32)> {
+        let epsilon = 1e-3 * self.cell_size().min_element();
+        let mut spacing = radius.max(epsilon);
+        let mut distance = 0.0;
+        for _ in 0..MAX_CAST_STEPS {
+            let offset = direction * distance;
+            let gap = self.segment_clearance(start + offset, end + offset, spacing) - radius;
+            if gap < epsilon {
+                let (point, value) = self.segment_deepest_point(start + offset, end + offset);
+                if value - radius < epsilon || spacing <= epsilon {
+                    return Some((distance, point, value));
+                }
+                spacing /= 2.0;
+                continue;
+            }
+            distance += gap;
+            if distance > max_distance {
+                return None;
+            }
+        }
+        None
+    }
+
+    // every point of the segment is within half of the spacing of a sample and the field is
+    // a distance bound, so no point of the segment is closer to the surface than this
+    fn segment_clearance(&self, start: Vec3, end: Vec3, spacing: f32) -> f32 {
+        let length = start.distance(end);
+        let samples = (length / spacing).ceil().max(1.0) as u32;
+        let min_value = (0..=samples)
+            .map(|i| self.field(start.lerp(end, i as f32 / samples as f32)))
+            .fold(f32::INFINITY, f32::min);
+        min_value - length / samples as f32 / 2.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::SystemState;
+
+    use super::*;
+
+    fn collisions_world() -> World {
+        let mut world = World::new();
+        let mut isosurfaces = Assets::<Isosurface>::default();
+        let handle = isosurfaces.add(Isosurface::default());
+        world.insert_resource(isosurfaces);
+        world.spawn((IsosurfaceHandle(handle), GlobalTransform::IDENTITY));
+        world
+    }
+
+    #[test]
+    fn sphere_inside_torus_penetrates() {
+        let mut world = collisions_world();
+        let mut state = SystemState::<IsosurfaceCollisions>::new(&mut world);
+        let collisions = state.get(&world);
+
+        // the tube surface is 1 above the center, the sphere reaches 0.5 further
+        let contacts = collisions.sphere_contacts(Sphere::new(0.5), Vec3::new(3.0, 0.0, 1.0), 1.0);
+        assert_eq!(contacts.len(), 1);
+        assert!((contacts[0].penetration - 1.5).abs() < 1e-3);
+        assert!(contacts[0].normal.dot(Vec3::Z) > 0.99);
+        assert!(contacts[0].point.distance(Vec3::new(3.0, 0.0, 2.0)) < 1e-3);
+    }
+
+    #[test]
+    fn sphere_outside_torus_respects_max_distance() {
+        let mut world = collisions_world();
+        let mut state = SystemState::<IsosurfaceCollisions>::new(&mut world);
+        let collisions = state.get(&world);
+
+        // 2 above the tube surface, the gap to the sphere is 1.5
+        let center = Vec3::new(3.0, 0.0, 4.0);
+        assert!(collisions
+            .sphere_contacts(Sphere::new(0.5), center, 1.0)
+            .is_empty());
+
+        let contacts = collisions.sphere_contacts(Sphere::new(0.5), center, 2.0);
+        assert_eq!(contacts.len(), 1);
+        assert!((contacts[0].penetration + 1.5).abs() < 1e-3);
+    }
+}
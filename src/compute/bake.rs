@@ -0,0 +1,193 @@
+use bevy::{
+    asset::RenderAssetUsages,
+    prelude::*,
+    render::{
+        render_asset::RenderAssets,
+        render_resource::{
+            BindGroup, BindGroupEntry, BindingResource, BufferInitDescriptor, BufferUsages,
+            Extent3d, PipelineCache, TextureDimension, TextureFormat, TextureUsages,
+        },
+        renderer::RenderDevice,
+        texture::GpuImage,
+    },
+};
+
+use crate::{ChangedIsosurfaces, ComputeIsosurface, Isosurface};
+
+use super::{
+    pipeline::{IsosurfaceComputePipelines, IsosurfaceUniforms},
+    ComputeResults,
+};
+
+// bakes the field into a 3d texture for other shaders, `IsosurfaceSdfImage` is inserted
+// next to it and baked again whenever the isosurface changes. there is one texel per cell
+// with the field at the center of the cell, so the texture covers the grid box
+#[derive(Component, Clone, Copy, Debug, Default, Reflect, PartialEq, Eq)]
+#[reflect(Component, Default)]
+pub struct IsosurfaceSdfBake {
+    pub format: IsosurfaceSdfFormat,
+}
+
+#[derive(Clone, Copy, Debug, Default, Reflect, PartialEq, Eq, Hash)]
+pub enum IsosurfaceSdfFormat {
+    // filterable, but can be written only on gpus supporting it as a storage format.
+    // nothing is baked on other ones
+    R16Float,
+    // not filterable without `WgpuFeatures::FLOAT32_FILTERABLE`, sample it with a nearest sampler
+    #[default]
+    R32Float,
+}
+
+impl From<IsosurfaceSdfFormat> for TextureFormat {
+    fn from(format: IsosurfaceSdfFormat) -> Self {
+        match format {
+            IsosurfaceSdfFormat::R16Float => TextureFormat::R16Float,
+            IsosurfaceSdfFormat::R32Float => TextureFormat::R32Float,
+        }
+    }
+}
+
+// baked field in the red channel, the image is replaced in place so the handle stays the same
+#[derive(Component, Clone, Debug, Deref, Reflect, PartialEq, Eq)]
+#[reflect(Component)]
+pub struct IsosurfaceSdfImage(pub Handle<Image>);
+
+#[derive(Clone, Copy, Debug)]
+pub struct SdfBakeRequest {
+    isosurface_id: AssetId<Isosurface>,
+    image_id: AssetId<Image>,
+    format: IsosurfaceSdfFormat,
+}
+
+// requests wait until the image and the isosurface are prepared in the render world
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct SdfBakeRequests(Vec<SdfBakeRequest>);
+
+pub struct SdfBakeDispatch {
+    pub bind_group: BindGroup,
+    pub format: IsosurfaceSdfFormat,
+    pub workgroups: UVec3,
+}
+
+// dispatched by the compute node this frame
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct SdfBakeDispatches(Vec<SdfBakeDispatch>);
+
+fn sdf_image(cells: UVec3, format: IsosurfaceSdfFormat) -> Image {
+    let texture_format = TextureFormat::from(format);
+    let texel_size = texture_format.block_copy_size(None).unwrap_or(4) as usize;
+    let mut image = Image::new_fill(
+        Extent3d {
+            width: cells.x,
+            height: cells.y,
+            depth_or_array_layers: cells.z,
+        },
+        TextureDimension::D3,
+        &vec![0; texel_size],
+        texture_format,
+        RenderAssetUsages::RENDER_WORLD,
+    );
+    image.texture_descriptor.usage |= TextureUsages::STORAGE_BINDING;
+    image
+}
+
+pub fn request_sdf_bakes(
+    mut commands: Commands,
+    mut changed: ChangedIsosurfaces<IsosurfaceSdfBake>,
+    mut images: ResMut<Assets<Image>>,
+    requests: Res<ComputeResults<SdfBakeRequest>>,
+    sdf_images: Query<&IsosurfaceSdfImage>,
+) {
+    for (entity, isosurface_id, isosurface, bake) in changed.read() {
+        // the grid or the format could change, so the image is always created again
+        let image = sdf_image(isosurface.cells(), bake.format);
+        let image_id = match sdf_images.get(entity).ok() {
+            Some(sdf_image_handle) => {
+                images.insert(&sdf_image_handle.0, image);
+                sdf_image_handle.id()
+            }
+            None => {
+                let handle = images.add(image);
+                let image_id = handle.id();
+                commands.entity(entity).insert(IsosurfaceSdfImage(handle));
+                image_id
+            }
+        };
+        requests.push(SdfBakeRequest {
+            isosurface_id,
+            image_id,
+            format: bake.format,
+        });
+    }
+}
+
+pub fn extract_sdf_bake_requests(
+    mut pending: ResMut<SdfBakeRequests>,
+    requests: Res<ComputeResults<SdfBakeRequest>>,
+) {
+    for request in requests.drain() {
+        // a newer request for the same image replaces the old one
+        pending.retain(|queued| queued.image_id != request.image_id);
+        pending.push(request);
+    }
+}
+
+pub fn prepare_sdf_bake_bind_groups(
+    render_device: Res<RenderDevice>,
+    pipelines: Res<IsosurfaceComputePipelines>,
+    pipeline_cache: Res<PipelineCache>,
+    assets: Res<RenderAssets<ComputeIsosurface>>,
+    images: Res<RenderAssets<GpuImage>>,
+    mut requests: ResMut<SdfBakeRequests>,
+    mut dispatches: ResMut<SdfBakeDispatches>,
+) {
+    requests.retain(|request| {
+        let Some(pipeline) = pipelines.bake_sdf_pipeline(request.format) else {
+            warn_once!("{:?} sdf textures aren't supported", request.format);
+            return false;
+        };
+        if pipeline_cache
+            .get_compute_pipeline(pipeline.pipeline)
+            .is_none()
+        {
+            return true;
+        }
+        let (Some(asset), Some(image)) = (
+            assets.get(request.isosurface_id),
+            images.get(request.image_id),
+        ) else {
+            return true;
+        };
+
+        let uniforms = IsosurfaceUniforms::from(asset);
+        let uniform_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("isosurface bake sdf uniform buffer"),
+            contents: bytemuck::bytes_of(&uniforms),
+            usage: BufferUsages::UNIFORM,
+        });
+        let bind_group = render_device.create_bind_group(
+            None,
+            &pipeline.bind_group_layout,
+            &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 7,
+                    resource: BindingResource::TextureView(&image.texture_view),
+                },
+            ],
+        );
+        dispatches.push(SdfBakeDispatch {
+            bind_group,
+            format: request.format,
+            workgroups: asset.grid_density,
+        });
+        false
+    });
+}
+
+pub fn clear_sdf_bake_dispatches(mut dispatches: ResMut<SdfBakeDispatches>) {
+    dispatches.clear();
+}
@@ -1,49 +1,190 @@
+mod bake;
 mod node;
 mod pipeline;
 
+use std::{
+    cmp::Ordering,
+    sync::{Arc, Mutex},
+};
+
+pub use bake::{IsosurfaceSdfBake, IsosurfaceSdfFormat, IsosurfaceSdfImage};
 use bevy::{
     app::{App, Plugin},
     core_pipeline::core_3d::graph::{Core3d, Node3d},
     prelude::*,
     render::{
-        render_graph::RenderGraphApp, render_resource::PipelineCache, Render, RenderApp, RenderSet,
+        extract_resource::ExtractResourcePlugin,
+        primitives::Aabb,
+        render_asset::{prepare_assets, RenderAssets},
+        render_graph::RenderGraphApp,
+        render_resource::{MapMode, PipelineCache},
+        Extract, Render, RenderApp, RenderSet,
     },
     utils::HashMap,
 };
+
+use bake::{
+    clear_sdf_bake_dispatches, extract_sdf_bake_requests, prepare_sdf_bake_bind_groups,
+    request_sdf_bakes, SdfBakeDispatches, SdfBakeRequest, SdfBakeRequests,
+};
 use pipeline::{
     allocate_buffers, check_pipeline_for_readiness, prepare_bind_groups, prepare_buffers,
     BuildIndirectBufferBindGroups, CalculateIsosurfaceBindGroups, IndirectBuffersCollection,
     IsosurfaceBuffersCollection, PipelinesReady,
 };
 
-use crate::Isosurface;
+pub(crate) use pipeline::IsosurfaceRenderResources;
+
+use crate::{
+    ComputeIsosurface, Isosurface, IsosurfaceComputeBudget, IsosurfaceHandle, IsosurfacePriority,
+    RenderMeshRegistry,
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CalculateIsosurfaceTaskState {
+    // doesn't fit into the frame budget, task waits for one of the next frames
+    #[default]
+    Queued,
+    // picked for this frame, waits for buffers, bind groups and mesh slices
+    Pending,
+    // everything is prepared, compute node dispatches the task this frame
+    Ready,
+}
+
+// the task writes into the back mesh of the isosurface, it's looked up in `RenderMeshRegistry`
+// every frame, so a task waiting for the budget never writes into a mesh swapped to the front
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CalculateIsosurfaceTask {
+    pub state: CalculateIsosurfaceTaskState,
+}
 
 #[derive(Resource, Default, Deref, DerefMut)]
-pub struct CalculateIsosurfaceTasks(HashMap<AssetId<Isosurface>, AssetId<Mesh>>);
+pub struct CalculateIsosurfaceTasks(HashMap<AssetId<Isosurface>, CalculateIsosurfaceTask>);
+
+// mailbox shared by the main and the render world. results of dispatched tasks are sent
+// back to the main world, sdf bake requests go the other way
+#[derive(Resource)]
+pub struct ComputeResults<T>(Arc<Mutex<Vec<T>>>);
+
+impl<T> Default for ComputeResults<T> {
+    fn default() -> Self {
+        Self(Arc::default())
+    }
+}
+
+impl<T> Clone for ComputeResults<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> ComputeResults<T> {
+    pub fn push(&self, result: T) {
+        self.0.lock().unwrap().push(result);
+    }
+
+    pub fn drain(&self) -> Vec<T> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+// the mesh is swapped in as the front mesh of its isosurface
+pub struct ComputedMesh {
+    pub isosurface_id: AssetId<Isosurface>,
+    pub mesh_id: AssetId<Mesh>,
+}
+
+// tight bounds of the generated vertices, only for isosurfaces with `tight_bounds`
+pub struct ComputedBounds {
+    pub isosurface_id: AssetId<Isosurface>,
+    // the mesh the vertices were written to
+    pub mesh_id: AssetId<Mesh>,
+    pub aabb: Aabb,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IsosurfaceTaskPriority {
+    pub priority: i32,
+    // distance from the closest active camera
+    pub distance: f32,
+}
+
+impl Default for IsosurfaceTaskPriority {
+    fn default() -> Self {
+        Self {
+            priority: 0,
+            distance: f32::INFINITY,
+        }
+    }
+}
+
+impl IsosurfaceTaskPriority {
+    // higher priority goes first, closer to the camera goes first among equal priorities
+    fn cmp_dispatch_order(&self, other: &Self) -> Ordering {
+        other
+            .priority
+            .cmp(&self.priority)
+            .then_with(|| self.distance.total_cmp(&other.distance))
+    }
+}
+
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct IsosurfaceTaskPriorities(HashMap<AssetId<Isosurface>, IsosurfaceTaskPriority>);
 
 pub struct ComputeIsosurfacePlugin;
 
 impl Plugin for ComputeIsosurfacePlugin {
     fn build(&self, app: &mut App) {
+        let computed_meshes = ComputeResults::<ComputedMesh>::default();
+        let computed_bounds = ComputeResults::<ComputedBounds>::default();
+        let sdf_bake_requests = ComputeResults::<SdfBakeRequest>::default();
+        app.init_resource::<IsosurfaceComputeBudget>()
+            .insert_resource(computed_meshes.clone())
+            .insert_resource(computed_bounds.clone())
+            .insert_resource(sdf_bake_requests.clone())
+            .add_systems(PostUpdate, request_sdf_bakes)
+            .register_type::<IsosurfaceSdfBake>()
+            .register_type::<IsosurfaceSdfImage>()
+            .add_plugins(ExtractResourcePlugin::<IsosurfaceComputeBudget>::default());
+
         app.sub_app_mut(RenderApp)
+            .insert_resource(computed_meshes)
+            .insert_resource(computed_bounds)
+            .insert_resource(sdf_bake_requests)
+            .add_systems(
+                ExtractSchedule,
+                (extract_task_priorities, extract_sdf_bake_requests),
+            )
             .add_systems(
                 Render,
                 (
+                    select_tasks_within_budget
+                        .in_set(RenderSet::Queue)
+                        .after(crate::schedule_isosurface_tasks),
                     check_pipeline_for_readiness
                         .in_set(RenderSet::Render)
                         .after(PipelineCache::process_pipeline_queue_system),
                     prepare_buffers.in_set(RenderSet::PrepareResources),
                     prepare_bind_groups.in_set(RenderSet::PrepareBindGroups),
-                    allocate_buffers.in_set(RenderSet::PrepareAssets),
-                    clear_finished_tasks.in_set(RenderSet::Cleanup),
+                    prepare_sdf_bake_bind_groups.in_set(RenderSet::PrepareBindGroups),
+                    allocate_buffers
+                        .in_set(RenderSet::PrepareAssets)
+                        .after(prepare_assets::<ComputeIsosurface>),
+                    (read_back_bounds, clear_finished_tasks)
+                        .chain()
+                        .in_set(RenderSet::Cleanup),
+                    clear_sdf_bake_dispatches.in_set(RenderSet::Cleanup),
                 ),
             )
             .init_resource::<CalculateIsosurfaceTasks>()
+            .init_resource::<IsosurfaceTaskPriorities>()
             .init_resource::<IndirectBuffersCollection>()
             .init_resource::<IsosurfaceBuffersCollection>()
             .init_resource::<CalculateIsosurfaceBindGroups>()
             .init_resource::<BuildIndirectBufferBindGroups>()
             .init_resource::<PipelinesReady>()
+            .init_resource::<SdfBakeRequests>()
+            .init_resource::<SdfBakeDispatches>()
             .add_render_graph_node::<node::IsosurfaceComputeNode>(
                 Core3d,
                 node::IsosurfaceComputeNodeLabel,
@@ -57,11 +198,171 @@ impl Plugin for ComputeIsosurfacePlugin {
     }
 }
 
+fn extract_task_priorities(
+    mut priorities: ResMut<IsosurfaceTaskPriorities>,
+    isosurfaces: Extract<
+        Query<(
+            &IsosurfaceHandle,
+            &GlobalTransform,
+            Option<&IsosurfacePriority>,
+        )>,
+    >,
+    cameras: Extract<Query<(&Camera, &GlobalTransform)>>,
+    isosurface_assets: Extract<Res<Assets<Isosurface>>>,
+) {
+    priorities.clear();
+    for (isosurface_handle, transform, priority) in isosurfaces.iter() {
+        // chunks of a volume share the transform of the volume, their grid boxes differ
+        let center = isosurface_assets
+            .get(isosurface_handle.id())
+            .map_or(transform.translation(), |isosurface| {
+                transform.transform_point(isosurface.grid_origin)
+            });
+        let distance = cameras
+            .iter()
+            .filter(|(camera, _)| camera.is_active)
+            .map(|(_, camera_transform)| camera_transform.translation().distance(center))
+            .fold(f32::INFINITY, f32::min);
+        let priority = priority.map(|priority| priority.0).unwrap_or_default();
+
+        // the same isosurface can be used by several entities, the most urgent one wins
+        let entry = priorities.entry(isosurface_handle.id()).or_default();
+        entry.priority = entry.priority.max(priority);
+        entry.distance = entry.distance.min(distance);
+    }
+}
+
+// picks tasks for this frame in priority order until the budget is exhausted.
+// the first task is always picked so a task larger than the budget can't get stuck
+fn select_tasks_within_budget(
+    budget: Res<IsosurfaceComputeBudget>,
+    priorities: Res<IsosurfaceTaskPriorities>,
+    assets: Res<RenderAssets<ComputeIsosurface>>,
+    mut tasks: ResMut<CalculateIsosurfaceTasks>,
+) {
+    let mut order: Vec<_> = tasks
+        .keys()
+        .map(|asset_id| {
+            let priority = priorities.get(asset_id).copied().unwrap_or_default();
+            (*asset_id, priority)
+        })
+        .collect();
+    order.sort_by(|(_, a), (_, b)| a.cmp_dispatch_order(b));
+
+    let mut cells = 0;
+    let mut dispatches = 0;
+    for (asset_id, _) in order {
+        let Some(task) = tasks.get_mut(&asset_id) else {
+            continue;
+        };
+        task.state = CalculateIsosurfaceTaskState::Queued;
+
+        let Some(isosurface) = assets.get(asset_id) else {
+            continue;
+        };
+        let task_cells = isosurface.extent_cells_count();
+
+        let within_budget = budget
+            .max_dispatches_per_frame
+            .is_none_or(|max| dispatches < max)
+            && budget
+                .max_cells_per_frame
+                .is_none_or(|max| cells + task_cells <= max);
+        if dispatches > 0 && !within_budget {
+            continue;
+        }
+
+        cells += task_cells;
+        dispatches += 1;
+        task.state = CalculateIsosurfaceTaskState::Pending;
+    }
+}
+
+// maps the bounds copied by the compute node, decoded bounds are sent to the main world
+// as soon as the gpu is done with them
+fn read_back_bounds(
+    tasks: Res<CalculateIsosurfaceTasks>,
+    pipelines_ready: Res<PipelinesReady>,
+    buffers: Res<IsosurfaceBuffersCollection>,
+    computed_bounds: Res<ComputeResults<ComputedBounds>>,
+    mesh_registry: Res<RenderMeshRegistry>,
+) {
+    if !pipelines_ready.0 {
+        return;
+    }
+    for (isosurface_id, task) in tasks.iter() {
+        if task.state != CalculateIsosurfaceTaskState::Ready {
+            continue;
+        }
+        let Some(readback_buffer) = buffers
+            .get(isosurface_id)
+            .and_then(|buffers| buffers.bounds_readback_buffer.clone())
+        else {
+            continue;
+        };
+        let Some(mesh_id) = mesh_registry.get(isosurface_id).copied() else {
+            continue;
+        };
+        let isosurface_id = *isosurface_id;
+        let computed_bounds = computed_bounds.clone();
+        let buffer = readback_buffer.clone();
+        readback_buffer
+            .slice(..)
+            .map_async(MapMode::Read, move |result| {
+                if let Err(err) = result {
+                    error!("failed to map isosurface bounds buffer: {err}");
+                    return;
+                }
+                let data = buffer.slice(..).get_mapped_range();
+                let bounds: [u32; 6] = bytemuck::pod_read_unaligned(&data);
+                drop(data);
+                buffer.unmap();
+                // there are no vertices if minimum is still greater than maximum
+                if (0..3).any(|i| bounds[i] > bounds[i + 3]) {
+                    return;
+                }
+                let [min_x, min_y, min_z, max_x, max_y, max_z] = bounds.map(from_ordered_bits);
+                computed_bounds.push(ComputedBounds {
+                    isosurface_id,
+                    mesh_id,
+                    aabb: Aabb::from_min_max(
+                        Vec3::new(min_x, min_y, min_z),
+                        Vec3::new(max_x, max_y, max_z),
+                    ),
+                });
+            });
+    }
+}
+
+// inverse of `ordered_bits` from the shader
+fn from_ordered_bits(bits: u32) -> f32 {
+    if bits & 0x8000_0000 != 0 {
+        f32::from_bits(bits & !0x8000_0000)
+    } else {
+        f32::from_bits(!bits)
+    }
+}
+
+// only tasks which were actually dispatched are removed, the rest stays queued
 fn clear_finished_tasks(
     mut tasks: ResMut<CalculateIsosurfaceTasks>,
-    pipelines_ready: ResMut<PipelinesReady>,
+    pipelines_ready: Res<PipelinesReady>,
+    computed_meshes: Res<ComputeResults<ComputedMesh>>,
+    mesh_registry: Res<RenderMeshRegistry>,
 ) {
-    if pipelines_ready.0 {
-        tasks.clear();
+    if !pipelines_ready.0 {
+        return;
     }
+    tasks.retain(|isosurface_id, task| {
+        if task.state != CalculateIsosurfaceTaskState::Ready {
+            return true;
+        }
+        if let Some(mesh_id) = mesh_registry.get(isosurface_id) {
+            computed_meshes.push(ComputedMesh {
+                isosurface_id: *isosurface_id,
+                mesh_id: *mesh_id,
+            });
+        }
+        false
+    });
 }
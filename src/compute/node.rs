@@ -8,11 +8,12 @@ use bevy::{
     },
 };
 
-use crate::ComputeIsosurface;
+use crate::{ComputeIsosurface, IsosurfaceOrdering};
 
 use super::{
-    pipeline::IsosurfaceComputePipelines, BuildIndirectBufferBindGroups,
-    CalculateIsosurfaceBindGroups, CalculateIsosurfaceTasks,
+    pipeline::{IsosurfaceComputePipelines, BOUNDS_SIZE},
+    BuildIndirectBufferBindGroups, CalculateIsosurfaceBindGroups, CalculateIsosurfaceTaskState,
+    CalculateIsosurfaceTasks, IsosurfaceBuffersCollection, SdfBakeDispatches,
 };
 
 #[derive(Default)]
@@ -31,14 +32,37 @@ impl render_graph::Node for IsosurfaceComputeNode {
         let compute_pipelines = world.resource::<IsosurfaceComputePipelines>();
         let pipeline_cache = world.resource::<PipelineCache>();
 
+        // bakes don't depend on the polygonization pipelines, their own ones are checked
+        // before the dispatches are prepared
+        let sdf_bakes = world.resource::<SdfBakeDispatches>();
+        if !sdf_bakes.is_empty() {
+            let encoder = render_context.command_encoder();
+            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+            for bake in sdf_bakes.iter() {
+                let Some(pipeline) = compute_pipelines
+                    .bake_sdf_pipeline(bake.format)
+                    .and_then(|pipeline| pipeline_cache.get_compute_pipeline(pipeline.pipeline))
+                else {
+                    continue;
+                };
+                pass.set_pipeline(pipeline);
+                pass.set_bind_group(0, &bake.bind_group, &[]);
+                pass.dispatch_workgroups(bake.workgroups.x, bake.workgroups.y, bake.workgroups.z);
+            }
+        }
+
         let (
             Some(find_vertices_pipeline),
             Some(connect_vertices_pipeline),
             Some(prepare_indirect_buffer_pipeline),
+            Some(count_cells_pipeline),
+            Some(scan_offsets_pipeline),
         ) = (
             pipeline_cache.get_compute_pipeline(compute_pipelines.find_vertices_pipeline),
             pipeline_cache.get_compute_pipeline(compute_pipelines.connect_vertices_pipeline),
             pipeline_cache.get_compute_pipeline(compute_pipelines.prepare_indirect_buffer_pipeline),
+            pipeline_cache.get_compute_pipeline(compute_pipelines.count_cells_pipeline),
+            pipeline_cache.get_compute_pipeline(compute_pipelines.scan_offsets_pipeline),
         )
         else {
             return Ok(());
@@ -51,8 +75,13 @@ impl render_graph::Node for IsosurfaceComputeNode {
         let calculate_tasks = world.resource::<CalculateIsosurfaceTasks>();
         let calculate_bind_groups = world.resource::<CalculateIsosurfaceBindGroups>();
         let build_indirect_buffer_bind_groups = world.resource::<BuildIndirectBufferBindGroups>();
+        let buffers = world.resource::<IsosurfaceBuffersCollection>();
+        let mut bounds_copies = Vec::new();
 
-        for (asset_id, _) in calculate_tasks.iter() {
+        for (asset_id, task) in calculate_tasks.iter() {
+            if task.state != CalculateIsosurfaceTaskState::Ready {
+                continue;
+            }
             let Some(calculate_bind_group) = calculate_bind_groups.get(asset_id) else {
                 error!("missing isosurface compute bind group");
                 continue;
@@ -62,11 +91,17 @@ impl render_graph::Node for IsosurfaceComputeNode {
                 error!("missing isosurface asset");
                 continue;
             };
-            let density = isosurface.grid_density;
+            let workgroups = isosurface.workgroups();
+            if isosurface.ordering == IsosurfaceOrdering::Deterministic {
+                pass.set_pipeline(count_cells_pipeline);
+                pass.dispatch_workgroups(workgroups.x, workgroups.y, workgroups.z);
+                pass.set_pipeline(scan_offsets_pipeline);
+                pass.dispatch_workgroups(1, 1, 1);
+            }
             pass.set_pipeline(find_vertices_pipeline);
-            pass.dispatch_workgroups(density.x, density.y, density.z);
+            pass.dispatch_workgroups(workgroups.x, workgroups.y, workgroups.z);
             pass.set_pipeline(connect_vertices_pipeline);
-            pass.dispatch_workgroups(density.x, density.y, density.z);
+            pass.dispatch_workgroups(workgroups.x, workgroups.y, workgroups.z);
 
             let Some(prepare_indirect_bind_group) = build_indirect_buffer_bind_groups.get(asset_id)
             else {
@@ -77,6 +112,18 @@ impl render_graph::Node for IsosurfaceComputeNode {
             pass.set_pipeline(prepare_indirect_buffer_pipeline);
             pass.dispatch_workgroups(1, 1, 1);
             info!("isosurface compute pass done");
+
+            if let Some(buffers) = buffers.get(asset_id) {
+                if let Some(readback_buffer) = &buffers.bounds_readback_buffer {
+                    bounds_copies.push((&buffers.bounds_buffer, readback_buffer));
+                }
+            }
+        }
+        drop(pass);
+
+        let encoder = render_context.command_encoder();
+        for (bounds_buffer, readback_buffer) in bounds_copies {
+            encoder.copy_buffer_to_buffer(bounds_buffer, 0, readback_buffer, 0, BOUNDS_SIZE);
         }
         Ok(())
     }
@@ -1,4 +1,5 @@
 use bevy::{
+    ecs::system::SystemParam,
     prelude::*,
     render::{
         mesh::allocator::{ElementClass, ElementLayout, MeshAllocator},
@@ -6,19 +7,23 @@ use bevy::{
         render_resource::{
             binding_types, BindGroup, BindGroupEntry, BindGroupLayout, BindGroupLayoutEntries,
             Buffer, BufferDescriptor, BufferInitDescriptor, BufferUsages, CachedComputePipelineId,
-            CachedPipelineState, ComputePipelineDescriptor, PipelineCache, ShaderStages,
-            ShaderType,
+            CachedPipelineState, ComputePipelineDescriptor, PipelineCache, ShaderDefVal,
+            ShaderStages, ShaderType, StorageTextureAccess, TextureFormat, TextureUsages,
         },
-        renderer::{RenderDevice, RenderQueue},
+        renderer::{RenderAdapter, RenderDevice, RenderQueue},
+        settings::WgpuFeatures,
     },
     utils::HashMap,
 };
 
 use std::{borrow::Cow, num::NonZeroU64};
 
-use crate::{ComputeIsosurface, Isosurface};
+use crate::{
+    ComputeIsosurface, Isosurface, IsosurfaceBoundary, IsosurfaceNormals, IsosurfaceOrdering,
+    RenderMeshRegistry,
+};
 
-use super::CalculateIsosurfaceTasks;
+use super::{CalculateIsosurfaceTaskState, CalculateIsosurfaceTasks, IsosurfaceSdfFormat};
 
 #[derive(Resource)]
 pub struct IsosurfaceComputePipelines {
@@ -29,6 +34,29 @@ pub struct IsosurfaceComputePipelines {
 
     pub find_vertices_pipeline: CachedComputePipelineId,
     pub connect_vertices_pipeline: CachedComputePipelineId,
+
+    // deterministic ordering only
+    pub count_cells_pipeline: CachedComputePipelineId,
+    pub scan_offsets_pipeline: CachedComputePipelineId,
+
+    pub bake_sdf_r32_pipeline: BakeSdfPipeline,
+    // r16float storage textures aren't supported everywhere
+    pub bake_sdf_r16_pipeline: Option<BakeSdfPipeline>,
+}
+
+impl IsosurfaceComputePipelines {
+    pub fn bake_sdf_pipeline(&self, format: IsosurfaceSdfFormat) -> Option<&BakeSdfPipeline> {
+        match format {
+            IsosurfaceSdfFormat::R16Float => self.bake_sdf_r16_pipeline.as_ref(),
+            IsosurfaceSdfFormat::R32Float => Some(&self.bake_sdf_r32_pipeline),
+        }
+    }
+}
+
+// layout of the bake depends on the texture format
+pub struct BakeSdfPipeline {
+    pub bind_group_layout: BindGroupLayout,
+    pub pipeline: CachedComputePipelineId,
 }
 
 #[derive(Resource, Default, Deref, DerefMut)]
@@ -38,6 +66,11 @@ pub struct IsosurfaceBuffers {
     pub uniform_buffer: Buffer,
     pub cells_buffer: Buffer,
     pub atomics_buffer: Buffer,
+    pub bounds_buffer: Buffer,
+    // per cell vertex and quad offsets, used only for deterministic ordering
+    pub offsets_buffer: Buffer,
+    // bounds are copied here when isosurface asks for tight bounds
+    pub bounds_readback_buffer: Option<Buffer>,
 }
 
 #[derive(Resource, Default, Deref, DerefMut)]
@@ -54,15 +87,71 @@ pub struct IsosurfaceUniforms {
     _padding0: u32,
     pub grid_origin: Vec3,
     _padding1: u32,
+    pub cells: UVec3,
+    _padding2: u32,
+    pub apron_min: UVec3,
+    _padding3: u32,
+    pub apron_max: UVec3,
+    _padding4: u32,
+    pub apron_scale_min: UVec3,
+    _padding5: u32,
+    pub apron_scale_max: UVec3,
+    pub capped: u32,
+    pub gradient_normals: u32,
+    pub projection_iterations: u32,
+    pub projection_tolerance: f32,
+    pub deterministic: u32,
+    pub vertex_attributes: u32,
+    pub uv_scale: f32,
+    pub materials: u32,
+    pub occlusion_samples: u32,
+    pub occlusion_distance: f32,
+    pub occlusion_strength: f32,
+    _padding7: u32,
+    _padding8: u32,
+    pub edge_scales: [UVec4; 3],
 }
 
-impl IsosurfaceUniforms {
-    pub fn new(grid_size: Vec3, grid_origin: Vec3) -> Self {
+impl From<&ComputeIsosurface> for IsosurfaceUniforms {
+    fn from(asset: &ComputeIsosurface) -> Self {
         Self {
-            grid_size,
+            grid_size: asset.grid_size,
             _padding0: 0,
-            grid_origin,
+            grid_origin: asset.grid_origin,
             _padding1: 0,
+            cells: asset.cells(),
+            _padding2: 0,
+            apron_min: asset.stitching.apron_min(),
+            _padding3: 0,
+            apron_max: asset.stitching.apron_max(),
+            _padding4: 0,
+            apron_scale_min: asset.stitching.apron_scale_min(),
+            _padding5: 0,
+            apron_scale_max: asset.stitching.apron_scale_max(),
+            capped: (asset.boundary == IsosurfaceBoundary::Capped) as u32,
+            gradient_normals: (asset.normals == IsosurfaceNormals::Gradient) as u32,
+            projection_iterations: asset
+                .projection
+                .map_or(0, |projection| projection.iterations),
+            projection_tolerance: asset
+                .projection
+                .map_or(0.0, |projection| projection.tolerance),
+            deterministic: (asset.ordering == IsosurfaceOrdering::Deterministic) as u32,
+            vertex_attributes: asset.vertex_layout.bits(),
+            uv_scale: asset.uvs.map_or(1.0, |uvs| uvs.scale),
+            materials: asset.materials as u32,
+            occlusion_samples: asset
+                .ambient_occlusion
+                .map_or(0, |occlusion| occlusion.samples),
+            occlusion_distance: asset
+                .ambient_occlusion
+                .map_or(0.0, |occlusion| occlusion.distance),
+            occlusion_strength: asset
+                .ambient_occlusion
+                .map_or(0.0, |occlusion| occlusion.strength),
+            _padding7: 0,
+            _padding8: 0,
+            edge_scales: asset.stitching.edge_scales(),
         }
     }
 }
@@ -73,6 +162,17 @@ pub struct CalculateIsosurfaceBindGroups(HashMap<AssetId<Isosurface>, BindGroup>
 #[derive(Resource, Default, Deref, DerefMut)]
 pub struct BuildIndirectBufferBindGroups(HashMap<AssetId<Isosurface>, BindGroup>);
 
+// min and max of generated vertices, 3 + 3 floats encoded as order preserving u32
+pub const BOUNDS_SIZE: u64 = 6 * std::mem::size_of::<u32>() as u64;
+
+// used only to get its size, one per cell with a vertex
+#[derive(ShaderType)]
+#[repr(C)]
+pub struct VertexInfo {
+    flat_cell_index: u32,
+    intersections_bitmask: u32,
+}
+
 // used only to get it's sizeof
 #[derive(ShaderType)]
 #[repr(C)]
@@ -87,6 +187,25 @@ pub struct DrawIndexedIndirect {
 #[derive(Resource, Default, Deref, DerefMut)]
 pub struct IndirectBuffersCollection(HashMap<AssetId<Isosurface>, IndirectBuffers>);
 
+// everything the render world keeps per isosurface between frames, released together
+// when the isosurface is removed so the buffers and the mesh slabs bound by them are freed
+#[derive(SystemParam)]
+pub struct IsosurfaceRenderResources<'w> {
+    buffers: ResMut<'w, IsosurfaceBuffersCollection>,
+    indirect_buffers: ResMut<'w, IndirectBuffersCollection>,
+    calculate_bind_groups: ResMut<'w, CalculateIsosurfaceBindGroups>,
+    indirect_bind_groups: ResMut<'w, BuildIndirectBufferBindGroups>,
+}
+
+impl IsosurfaceRenderResources<'_> {
+    pub fn remove(&mut self, id: &AssetId<Isosurface>) {
+        self.buffers.remove(id);
+        self.indirect_buffers.remove(id);
+        self.calculate_bind_groups.remove(id);
+        self.indirect_bind_groups.remove(id);
+    }
+}
+
 impl FromWorld for IsosurfaceComputePipelines {
     fn from_world(world: &mut World) -> Self {
         let render_device = world.resource::<RenderDevice>();
@@ -106,6 +225,10 @@ impl FromWorld for IsosurfaceComputePipelines {
                     binding_types::storage_buffer_sized(false, NonZeroU64::new(1024)),
                     // Atomics
                     binding_types::storage_buffer_sized(false, None),
+                    // Bounds
+                    binding_types::storage_buffer_sized(false, NonZeroU64::new(BOUNDS_SIZE)),
+                    // Offsets
+                    binding_types::storage_buffer_sized(false, None),
                 ),
             ),
         );
@@ -158,6 +281,51 @@ impl FromWorld for IsosurfaceComputePipelines {
                 entry_point: Cow::from("prepare_indirect_buffer"),
             });
 
+        let count_cells_pipeline =
+            pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+                label: Some("isosurface count_cells pipeline".into()),
+                layout: vec![calculation_bind_group_layout.clone()],
+                push_constant_ranges: Vec::new(),
+                shader: shader.clone(),
+                shader_defs: vec![],
+                entry_point: Cow::from("count_cells"),
+            });
+
+        let scan_offsets_pipeline =
+            pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+                label: Some("isosurface scan_offsets pipeline".into()),
+                layout: vec![calculation_bind_group_layout.clone()],
+                push_constant_ranges: Vec::new(),
+                shader: shader.clone(),
+                shader_defs: vec![],
+                entry_point: Cow::from("scan_offsets"),
+            });
+
+        let bake_sdf_r32_pipeline = queue_bake_sdf_pipeline(
+            render_device,
+            pipeline_cache,
+            &shader,
+            IsosurfaceSdfFormat::R32Float,
+        );
+        // the feature only allows what the adapter reports, the adapter has to report
+        // storage usage of the format too
+        let r16_storage = render_device
+            .features()
+            .contains(WgpuFeatures::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES)
+            && world
+                .resource::<RenderAdapter>()
+                .get_texture_format_features(TextureFormat::R16Float)
+                .allowed_usages
+                .contains(TextureUsages::STORAGE_BINDING);
+        let bake_sdf_r16_pipeline = r16_storage.then(|| {
+            queue_bake_sdf_pipeline(
+                render_device,
+                pipeline_cache,
+                &shader,
+                IsosurfaceSdfFormat::R16Float,
+            )
+        });
+
         info!("pipelines are queued");
         IsosurfaceComputePipelines {
             calculation_bind_group_layout,
@@ -165,10 +333,58 @@ impl FromWorld for IsosurfaceComputePipelines {
             find_vertices_pipeline,
             connect_vertices_pipeline,
             prepare_indirect_buffer_pipeline,
+            count_cells_pipeline,
+            scan_offsets_pipeline,
+            bake_sdf_r32_pipeline,
+            bake_sdf_r16_pipeline,
         }
     }
 }
 
+fn queue_bake_sdf_pipeline(
+    render_device: &RenderDevice,
+    pipeline_cache: &PipelineCache,
+    shader: &Handle<Shader>,
+    format: IsosurfaceSdfFormat,
+) -> BakeSdfPipeline {
+    // only the uniforms and the texture are used by bake_sdf
+    let bind_group_layout = render_device.create_bind_group_layout(
+        "isosurface bake sdf bind group layout",
+        &BindGroupLayoutEntries::with_indices(
+            ShaderStages::COMPUTE,
+            (
+                (
+                    0,
+                    binding_types::uniform_buffer::<IsosurfaceUniforms>(false),
+                ),
+                (
+                    7,
+                    binding_types::texture_storage_3d(
+                        TextureFormat::from(format),
+                        StorageTextureAccess::WriteOnly,
+                    ),
+                ),
+            ),
+        ),
+    );
+    let mut shader_defs = Vec::new();
+    if format == IsosurfaceSdfFormat::R16Float {
+        shader_defs.push(ShaderDefVal::from("SDF_R16FLOAT"));
+    }
+    let pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+        label: Some("isosurface bake_sdf pipeline".into()),
+        layout: vec![bind_group_layout.clone()],
+        push_constant_ranges: Vec::new(),
+        shader: shader.clone(),
+        shader_defs,
+        entry_point: Cow::from("bake_sdf"),
+    });
+    BakeSdfPipeline {
+        bind_group_layout,
+        pipeline,
+    }
+}
+
 pub fn prepare_buffers(
     render_device: Res<RenderDevice>,
     assets: Res<RenderAssets<ComputeIsosurface>>,
@@ -176,20 +392,22 @@ pub fn prepare_buffers(
     mut calculate_buffers_collection: ResMut<IsosurfaceBuffersCollection>,
     mut indirect_buffers_collection: ResMut<IndirectBuffersCollection>,
 ) {
-    for (asset_id, _) in tasks.iter() {
+    for (asset_id, task) in tasks.iter() {
+        if task.state == CalculateIsosurfaceTaskState::Queued {
+            continue;
+        }
+        // TODO: write new values instead of recreating this 3... buffers
+        let Some(asset) = assets.get(*asset_id) else {
+            error!("isosurface asset not found");
+            continue;
+        };
         let cells_buffer = render_device.create_buffer(&BufferDescriptor {
             label: Some("isosurface cells buffer"),
-            size: 1024 * 256,
+            size: asset.extent_cells_count() * std::mem::size_of::<VertexInfo>() as u64,
             usage: BufferUsages::STORAGE,
             mapped_at_creation: false,
         });
-
-        // TODO: write new values instead of recreating this 3... buffers
-        let Some(asset) = assets.get(*asset_id) else {
-            error!("isosurface asset not found");
-            return;
-        };
-        let uniforms = IsosurfaceUniforms::new(asset.grid_size, asset.grid_origin);
+        let uniforms = IsosurfaceUniforms::from(asset);
         let uniform_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
             label: Some("isosurface uniform buffer"),
             contents: bytemuck::bytes_of(&uniforms),
@@ -202,10 +420,41 @@ pub fn prepare_buffers(
             usage: BufferUsages::STORAGE,
         });
 
+        // minimum starts at the largest value and maximum at the smallest one
+        let bounds_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("isosurface bounds buffer"),
+            contents: bytemuck::cast_slice(&[u32::MAX, u32::MAX, u32::MAX, 0, 0, 0]),
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+        });
+
+        // vertex and quad offsets of every cell and their totals, see the shader
+        let offsets_size = match asset.ordering {
+            IsosurfaceOrdering::Atomic => 1,
+            IsosurfaceOrdering::Deterministic => 2 * (asset.extent_cells_count() + 1),
+        };
+        let offsets_buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some("isosurface offsets buffer"),
+            size: offsets_size * std::mem::size_of::<u32>() as u64,
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+
+        let bounds_readback_buffer = asset.tight_bounds.then(|| {
+            render_device.create_buffer(&BufferDescriptor {
+                label: Some("isosurface bounds readback buffer"),
+                size: BOUNDS_SIZE,
+                usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            })
+        });
+
         let calculate_buffers = IsosurfaceBuffers {
             cells_buffer,
             uniform_buffer,
             atomics_buffer,
+            bounds_buffer,
+            offsets_buffer,
+            bounds_readback_buffer,
         };
 
         let indirect_buffer = render_device.create_buffer(&BufferDescriptor {
@@ -226,20 +475,28 @@ pub fn prepare_bind_groups(
     isosurface_compute_pipeline: Res<IsosurfaceComputePipelines>,
     calculate_buffers: Res<IsosurfaceBuffersCollection>,
     indirect_buffers: Res<IndirectBuffersCollection>,
-    tasks: Res<CalculateIsosurfaceTasks>,
+    mut tasks: ResMut<CalculateIsosurfaceTasks>,
     mesh_allocator: Res<MeshAllocator>,
     mut calculate_bind_groups: ResMut<CalculateIsosurfaceBindGroups>,
     mut indirect_bind_groups: ResMut<BuildIndirectBufferBindGroups>,
+    mesh_registry: Res<RenderMeshRegistry>,
 ) {
-    for (asset_id, mesh_id) in tasks.iter() {
+    for (asset_id, task) in tasks.iter_mut() {
+        if task.state == CalculateIsosurfaceTaskState::Queued {
+            continue;
+        }
+        let Some(mesh_id) = mesh_registry.get(asset_id) else {
+            continue;
+        };
+
         let Some(calculate_buffers) = calculate_buffers.get(asset_id) else {
             info!("isosurface buffers not found");
-            return;
+            continue;
         };
 
         let Some(indirect_buffers) = indirect_buffers.get(asset_id) else {
             info!("isosurface buffers not found");
-            return;
+            continue;
         };
 
         let (Some(vertex_slice), Some(index_slice)) = (
@@ -274,6 +531,14 @@ pub fn prepare_bind_groups(
                     binding: 4,
                     resource: calculate_buffers.atomics_buffer.as_entire_binding(),
                 },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: calculate_buffers.bounds_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 6,
+                    resource: calculate_buffers.offsets_buffer.as_entire_binding(),
+                },
             ],
         );
         let indirect_bind_group = render_device.create_bind_group(
@@ -286,6 +551,7 @@ pub fn prepare_bind_groups(
         );
         indirect_bind_groups.insert(*asset_id, indirect_bind_group);
         calculate_bind_groups.insert(*asset_id, calculate_bind_group);
+        task.state = CalculateIsosurfaceTaskState::Ready;
     }
 }
 
@@ -294,10 +560,18 @@ pub fn check_pipeline_for_readiness(
     pipeline_cache: Res<PipelineCache>,
     mut is_ready: ResMut<PipelinesReady>,
 ) {
-    if let (CachedPipelineState::Ok(_), CachedPipelineState::Ok(_), CachedPipelineState::Ok(_)) = (
+    if let (
+        CachedPipelineState::Ok(_),
+        CachedPipelineState::Ok(_),
+        CachedPipelineState::Ok(_),
+        CachedPipelineState::Ok(_),
+        CachedPipelineState::Ok(_),
+    ) = (
         pipeline_cache.get_compute_pipeline_state(pipelines.find_vertices_pipeline),
         pipeline_cache.get_compute_pipeline_state(pipelines.connect_vertices_pipeline),
         pipeline_cache.get_compute_pipeline_state(pipelines.prepare_indirect_buffer_pipeline),
+        pipeline_cache.get_compute_pipeline_state(pipelines.count_cells_pipeline),
+        pipeline_cache.get_compute_pipeline_state(pipelines.scan_offsets_pipeline),
     ) {
         is_ready.0 = true;
     };
@@ -306,10 +580,15 @@ pub fn check_pipeline_for_readiness(
 pub fn allocate_buffers(
     mut mesh_allocator: ResMut<MeshAllocator>,
     tasks: Res<CalculateIsosurfaceTasks>,
+    assets: Res<RenderAssets<ComputeIsosurface>>,
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
+    mesh_registry: Res<RenderMeshRegistry>,
 ) {
-    for (_, mesh_id) in tasks.iter() {
+    for asset_id in tasks.keys() {
+        let Some(mesh_id) = mesh_registry.get(asset_id) else {
+            continue;
+        };
         if let (Some(_), Some(_)) = (
             mesh_allocator.mesh_vertex_slice(mesh_id),
             mesh_allocator.mesh_index_slice(mesh_id),
@@ -317,10 +596,11 @@ pub fn allocate_buffers(
             continue;
         };
 
-        let vertex_element_layout = ElementLayout::new(
-            ElementClass::Vertex,
-            Mesh::ATTRIBUTE_POSITION.format.size() + Mesh::ATTRIBUTE_NORMAL.format.size(),
-        );
+        let Some(asset) = assets.get(*asset_id) else {
+            continue;
+        };
+        let vertex_element_layout =
+            ElementLayout::new(ElementClass::Vertex, asset.vertex_layout.vertex_size());
         mesh_allocator.allocate_large(mesh_id, vertex_element_layout);
 
         let index_element_layout =
@@ -334,9 +614,13 @@ pub fn allocate_buffers(
         let Some(index_slab_id) = mesh_allocator.mesh_id_to_index_slab.get(mesh_id).copied() else {
             unreachable!();
         };
+        // every cell has at most one vertex and quads on three of its edges
+        let vertex_buffer_size = asset.extent_cells_count() * asset.vertex_layout.vertex_size();
+        let index_buffer_size =
+            asset.extent_cells_count() * 3 * 6 * std::mem::size_of::<u32>() as u64;
         mesh_allocator.copy_element_data(
             mesh_id,
-            1024 * 256,
+            vertex_buffer_size as usize,
             |_| {},
             BufferUsages::VERTEX | BufferUsages::STORAGE,
             vertex_slab_id,
@@ -345,7 +629,7 @@ pub fn allocate_buffers(
         );
         mesh_allocator.copy_element_data(
             mesh_id,
-            1024 * 256,
+            index_buffer_size as usize,
             |_| {},
             BufferUsages::INDEX | BufferUsages::STORAGE,
             index_slab_id,
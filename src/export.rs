@@ -0,0 +1,568 @@
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+};
+
+use bevy::{
+    prelude::*,
+    render::mesh::{PrimitiveTopology, VertexAttributeValues},
+};
+
+use crate::{Isosurface, IsosurfaceHandle, IsosurfaceMaterial};
+
+// writers for triangle list meshes, like the ones from `Isosurface::polygonize`.
+// positions are required, normals are written by formats which support them
+
+struct Triangles<'a> {
+    positions: &'a [[f32; 3]],
+    normals: Option<&'a [[f32; 3]]>,
+    indices: Vec<u32>,
+}
+
+impl<'a> Triangles<'a> {
+    fn from_mesh(mesh: &'a Mesh) -> io::Result<Self> {
+        if mesh.primitive_topology() != PrimitiveTopology::TriangleList {
+            return Err(invalid_mesh("only triangle lists can be exported"));
+        }
+        let Some(VertexAttributeValues::Float32x3(positions)) =
+            mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+        else {
+            return Err(invalid_mesh("mesh has no Float32x3 positions"));
+        };
+        let normals = match mesh.attribute(Mesh::ATTRIBUTE_NORMAL) {
+            Some(VertexAttributeValues::Float32x3(normals)) => Some(normals.as_slice()),
+            _ => None,
+        };
+        let indices = match mesh.indices() {
+            Some(indices) => indices.iter().map(|index| index as u32).collect(),
+            None => (0..positions.len() as u32).collect(),
+        };
+        Ok(Self {
+            positions,
+            normals,
+            indices,
+        })
+    }
+
+    fn triangles(&self) -> impl Iterator<Item = [u32; 3]> + '_ {
+        self.indices
+            .chunks_exact(3)
+            .map(|triangle| [triangle[0], triangle[1], triangle[2]])
+    }
+
+    fn triangle_count(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    // zero normals can come from cells where the field is flat, they are replaced by
+    // the normal of the triangles around the vertex
+    fn unit_normals(&self) -> Option<Vec<[f32; 3]>> {
+        let normals = self.normals?;
+        let mut face_normals = vec![Vec3::ZERO; self.positions.len()];
+        for triangle in self.triangles() {
+            let [a, b, c] = triangle.map(|index| Vec3::from_array(self.positions[index as usize]));
+            let face_normal = (b - a).cross(c - a);
+            for index in triangle {
+                face_normals[index as usize] += face_normal;
+            }
+        }
+        Some(
+            normals
+                .iter()
+                .zip(face_normals)
+                .map(|(normal, face_normal)| {
+                    Vec3::from_array(*normal)
+                        .try_normalize()
+                        .or_else(|| face_normal.try_normalize())
+                        .unwrap_or(Vec3::Y)
+                        .to_array()
+                })
+                .collect(),
+        )
+    }
+}
+
+fn invalid_mesh(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+// wavefront obj, as text
+pub fn write_obj(mesh: &Mesh, writer: &mut impl Write) -> io::Result<()> {
+    let triangles = Triangles::from_mesh(mesh)?;
+    for [x, y, z] in triangles.positions {
+        writeln!(writer, "v {x} {y} {z}")?;
+    }
+    if let Some(normals) = triangles.normals {
+        for [x, y, z] in normals {
+            writeln!(writer, "vn {x} {y} {z}")?;
+        }
+    }
+    // obj indices start from 1
+    for [a, b, c] in triangles
+        .triangles()
+        .map(|triangle| triangle.map(|index| index + 1))
+    {
+        if triangles.normals.is_some() {
+            writeln!(writer, "f {a}//{a} {b}//{b} {c}//{c}")?;
+        } else {
+            writeln!(writer, "f {a} {b} {c}")?;
+        }
+    }
+    Ok(())
+}
+
+// binary little endian ply
+pub fn write_ply(mesh: &Mesh, writer: &mut impl Write) -> io::Result<()> {
+    let triangles = Triangles::from_mesh(mesh)?;
+    writeln!(writer, "ply")?;
+    writeln!(writer, "format binary_little_endian 1.0")?;
+    writeln!(writer, "element vertex {}", triangles.positions.len())?;
+    writeln!(writer, "property float x")?;
+    writeln!(writer, "property float y")?;
+    writeln!(writer, "property float z")?;
+    if triangles.normals.is_some() {
+        writeln!(writer, "property float nx")?;
+        writeln!(writer, "property float ny")?;
+        writeln!(writer, "property float nz")?;
+    }
+    writeln!(writer, "element face {}", triangles.triangle_count())?;
+    writeln!(writer, "property list uchar uint vertex_indices")?;
+    writeln!(writer, "end_header")?;
+
+    for (i, position) in triangles.positions.iter().enumerate() {
+        write_f32s(writer, position)?;
+        if let Some(normals) = triangles.normals {
+            write_f32s(writer, &normals[i])?;
+        }
+    }
+    for triangle in triangles.triangles() {
+        writer.write_all(&[3])?;
+        for index in triangle {
+            writer.write_all(&index.to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+// binary stl, normals of triangles are calculated from their vertices
+pub fn write_stl(mesh: &Mesh, writer: &mut impl Write) -> io::Result<()> {
+    let triangles = Triangles::from_mesh(mesh)?;
+    let Ok(triangle_count) = u32::try_from(triangles.triangle_count()) else {
+        return Err(invalid_mesh("too many triangles for stl"));
+    };
+    writer.write_all(&[0; 80])?;
+    writer.write_all(&triangle_count.to_le_bytes())?;
+    for triangle in triangles.triangles() {
+        let [a, b, c] = triangle.map(|index| Vec3::from_array(triangles.positions[index as usize]));
+        let normal = (b - a).cross(c - a).normalize_or_zero();
+        write_f32s(writer, &normal.to_array())?;
+        for vertex in [a, b, c] {
+            write_f32s(writer, &vertex.to_array())?;
+        }
+        // attribute byte count
+        writer.write_all(&0u16.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn write_f32s(writer: &mut impl Write, values: &[f32]) -> io::Result<()> {
+    for value in values {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+const GLB_MAGIC: u32 = 0x4654_6c67;
+const GLB_JSON_CHUNK: u32 = 0x4e4f_534a;
+const GLB_BIN_CHUNK: u32 = 0x004e_4942;
+const GLTF_FLOAT: u32 = 5126;
+const GLTF_UNSIGNED_INT: u32 = 5125;
+const GLTF_ARRAY_BUFFER: u32 = 34962;
+const GLTF_ELEMENT_ARRAY_BUFFER: u32 = 34963;
+
+// binary gltf with one node per entity. meshes are polygonized on the cpu, the gpu ones
+// can't be read on the main world. `GlobalTransform` and base color, metallic, roughness,
+// emissive, alpha mode and double sidedness of `MeshMaterial3d<StandardMaterial>` or
+// `MeshMaterial3d<IsosurfaceMaterial>` are exported too
+pub fn write_glb(world: &World, entities: &[Entity], writer: &mut impl Write) -> io::Result<()> {
+    let isosurfaces = world.resource::<Assets<Isosurface>>();
+    let mut meshes = Vec::new();
+    for entity in entities {
+        let Some(isosurface) = world
+            .get::<IsosurfaceHandle>(*entity)
+            .and_then(|handle| isosurfaces.get(&handle.0))
+        else {
+            return Err(invalid_mesh(&format!("{entity} has no isosurface")));
+        };
+        meshes.push(isosurface.polygonize());
+    }
+
+    let mut json = GltfJson::default();
+    let mut bin = Vec::new();
+    for (entity, mesh) in entities.iter().zip(&meshes) {
+        let triangles = Triangles::from_mesh(mesh)?;
+        let material = entity_material(world, *entity);
+        let transform = world
+            .get::<GlobalTransform>(*entity)
+            .map(|transform| transform.compute_transform())
+            .unwrap_or_default();
+        json.add_node(&triangles, material, transform, &mut bin);
+    }
+
+    let json = json.finish(bin.len());
+    let json_length = json.len().next_multiple_of(4);
+    let bin_length = bin.len().next_multiple_of(4);
+    // binary chunk is optional, it's left out when there is no geometry at all
+    let bin_chunk_length = if bin_length > 0 { 8 + bin_length } else { 0 };
+    let Ok(total_length) = u32::try_from(12 + 8 + json_length + bin_chunk_length) else {
+        return Err(invalid_mesh("too much geometry for glb"));
+    };
+
+    writer.write_all(&GLB_MAGIC.to_le_bytes())?;
+    writer.write_all(&2u32.to_le_bytes())?;
+    writer.write_all(&total_length.to_le_bytes())?;
+    // json is padded with spaces and binary data with zeros
+    writer.write_all(&(json_length as u32).to_le_bytes())?;
+    writer.write_all(&GLB_JSON_CHUNK.to_le_bytes())?;
+    writer.write_all(json.as_bytes())?;
+    writer.write_all(&b"   "[..json_length - json.len()])?;
+    if bin_length > 0 {
+        writer.write_all(&(bin_length as u32).to_le_bytes())?;
+        writer.write_all(&GLB_BIN_CHUNK.to_le_bytes())?;
+        writer.write_all(&bin)?;
+        writer.write_all(&[0u8; 3][..bin_length - bin.len()])?;
+    }
+    Ok(())
+}
+
+fn entity_material(world: &World, entity: Entity) -> Option<&StandardMaterial> {
+    if let Some(material) = world.get::<MeshMaterial3d<StandardMaterial>>(entity) {
+        return world
+            .get_resource::<Assets<StandardMaterial>>()?
+            .get(&material.0);
+    }
+    if let Some(material) = world.get::<MeshMaterial3d<IsosurfaceMaterial>>(entity) {
+        return world
+            .get_resource::<Assets<IsosurfaceMaterial>>()?
+            .get(&material.0)
+            .map(|material| &material.base);
+    }
+    None
+}
+
+// json parts of the document, every array is kept as comma separated objects
+#[derive(Default)]
+struct GltfJson {
+    nodes: Vec<String>,
+    meshes: Vec<String>,
+    materials: Vec<String>,
+    buffer_views: Vec<String>,
+    accessors: Vec<String>,
+}
+
+impl GltfJson {
+    fn add_node(
+        &mut self,
+        triangles: &Triangles,
+        material: Option<&StandardMaterial>,
+        transform: Transform,
+        bin: &mut Vec<u8>,
+    ) {
+        let mut node = format!(
+            r#"{{"translation":{},"rotation":{},"scale":{}"#,
+            json_array(&transform.translation.to_array()),
+            json_array(&transform.rotation.to_array()),
+            json_array(&transform.scale.to_array()),
+        );
+        // accessors can't be empty, entities without triangles get a node without a mesh
+        if triangles.triangle_count() > 0 {
+            let mut attributes = format!(
+                r#""POSITION":{}"#,
+                self.add_vec3_accessor(triangles.positions, true, bin)
+            );
+            // gltf requires normals of unit length
+            if let Some(normals) = triangles.unit_normals() {
+                let accessor = self.add_vec3_accessor(&normals, false, bin);
+                write!(attributes, r#","NORMAL":{accessor}"#).unwrap();
+            }
+            let indices = self.add_indices_accessor(&triangles.indices, bin);
+            let mut primitive = format!(r#"{{"attributes":{{{attributes}}},"indices":{indices}"#);
+            if let Some(material) = material {
+                write!(primitive, r#","material":{}"#, self.materials.len()).unwrap();
+                self.materials.push(gltf_material(material));
+            }
+            primitive.push('}');
+            write!(node, r#","mesh":{}"#, self.meshes.len()).unwrap();
+            self.meshes
+                .push(format!(r#"{{"primitives":[{primitive}]}}"#));
+        }
+        node.push('}');
+        self.nodes.push(node);
+    }
+
+    fn add_buffer_view(&mut self, data: &[u8], target: u32, bin: &mut Vec<u8>) -> usize {
+        self.buffer_views.push(format!(
+            r#"{{"buffer":0,"byteOffset":{},"byteLength":{},"target":{target}}}"#,
+            bin.len(),
+            data.len(),
+        ));
+        bin.extend_from_slice(data);
+        self.buffer_views.len() - 1
+    }
+
+    // positions need min and max
+    fn add_vec3_accessor(&mut self, values: &[[f32; 3]], bounds: bool, bin: &mut Vec<u8>) -> usize {
+        let data: Vec<u8> = values
+            .iter()
+            .flatten()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        let buffer_view = self.add_buffer_view(&data, GLTF_ARRAY_BUFFER, bin);
+        let mut accessor = format!(
+            r#"{{"bufferView":{buffer_view},"componentType":{GLTF_FLOAT},"count":{},"type":"VEC3""#,
+            values.len(),
+        );
+        if bounds {
+            let (min, max) = values
+                .iter()
+                .map(|value| Vec3::from_array(*value))
+                .fold((Vec3::MAX, Vec3::MIN), |(min, max), value| {
+                    (min.min(value), max.max(value))
+                });
+            write!(
+                accessor,
+                r#","min":{},"max":{}"#,
+                json_array(&min.to_array()),
+                json_array(&max.to_array()),
+            )
+            .unwrap();
+        }
+        accessor.push('}');
+        self.accessors.push(accessor);
+        self.accessors.len() - 1
+    }
+
+    fn add_indices_accessor(&mut self, indices: &[u32], bin: &mut Vec<u8>) -> usize {
+        let data: Vec<u8> = indices
+            .iter()
+            .flat_map(|index| index.to_le_bytes())
+            .collect();
+        let buffer_view = self.add_buffer_view(&data, GLTF_ELEMENT_ARRAY_BUFFER, bin);
+        self.accessors.push(format!(
+            r#"{{"bufferView":{buffer_view},"componentType":{GLTF_UNSIGNED_INT},"count":{},"type":"SCALAR"}}"#,
+            indices.len(),
+        ));
+        self.accessors.len() - 1
+    }
+
+    fn finish(self, bin_length: usize) -> String {
+        let node_indices: Vec<String> = (0..self.nodes.len()).map(|i| i.to_string()).collect();
+        let mut json = format!(
+            r#"{{"asset":{{"version":"2.0","generator":"bevy_ugr"}},"scene":0,"scenes":[{{"nodes":[{}]}}],"nodes":[{}]"#,
+            node_indices.join(","),
+            self.nodes.join(","),
+        );
+        for (name, values) in [
+            ("meshes", &self.meshes),
+            ("materials", &self.materials),
+            ("bufferViews", &self.buffer_views),
+            ("accessors", &self.accessors),
+        ] {
+            if !values.is_empty() {
+                write!(json, r#","{name}":[{}]"#, values.join(",")).unwrap();
+            }
+        }
+        if bin_length > 0 {
+            write!(json, r#","buffers":[{{"byteLength":{bin_length}}}]"#).unwrap();
+        }
+        json.push('}');
+        json
+    }
+}
+
+// gltf colors are linear, emissive is clamped since its strength needs an extension.
+// gltf has only opaque, masked and blended materials, other blend modes are exported as blended
+fn gltf_material(material: &StandardMaterial) -> String {
+    let base_color = material.base_color.to_linear().to_f32_array();
+    let emissive = material
+        .emissive
+        .to_f32_array_no_alpha()
+        .map(|value| value.clamp(0.0, 1.0));
+    let alpha = match material.alpha_mode {
+        AlphaMode::Opaque => r#""alphaMode":"OPAQUE""#.to_string(),
+        AlphaMode::Mask(cutoff) => {
+            format!(
+                r#""alphaMode":"MASK","alphaCutoff":{}"#,
+                json_number(cutoff)
+            )
+        }
+        // bevy uses the same cutoff for alpha to coverage
+        AlphaMode::AlphaToCoverage => r#""alphaMode":"MASK","alphaCutoff":0.5"#.to_string(),
+        AlphaMode::Blend | AlphaMode::Premultiplied | AlphaMode::Add | AlphaMode::Multiply => {
+            r#""alphaMode":"BLEND""#.to_string()
+        }
+    };
+    format!(
+        r#"{{"pbrMetallicRoughness":{{"baseColorFactor":{},"metallicFactor":{},"roughnessFactor":{}}},"emissiveFactor":{},{alpha},"doubleSided":{}}}"#,
+        json_array(&base_color),
+        json_number(material.metallic),
+        json_number(material.perceptual_roughness),
+        json_array(&emissive),
+        material.double_sided,
+    )
+}
+
+fn json_array(values: &[f32]) -> String {
+    let values: Vec<String> = values.iter().map(|value| json_number(*value)).collect();
+    format!("[{}]", values.join(","))
+}
+
+// json has no nan or infinity, nan becomes 0 and infinities the largest finite values
+fn json_number(value: f32) -> String {
+    if value.is_nan() {
+        return "0".to_string();
+    }
+    value.clamp(f32::MIN, f32::MAX).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{asset::RenderAssetUsages, render::mesh::Indices};
+
+    use super::*;
+
+    // two triangles of a unit square
+    fn square() -> Mesh {
+        Mesh::new(
+            PrimitiveTopology::TriangleList,
+            RenderAssetUsages::default(),
+        )
+        .with_inserted_attribute(
+            Mesh::ATTRIBUTE_POSITION,
+            vec![
+                [0.0, 0.0, 0.0],
+                [1.0, 0.0, 0.0],
+                [0.0, 1.0, 0.0],
+                [1.0, 1.0, 0.0],
+            ],
+        )
+        .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[0.0, 0.0, 1.0]; 4])
+        .with_inserted_indices(Indices::U32(vec![0, 1, 2, 2, 1, 3]))
+    }
+
+    #[test]
+    fn obj_has_vertices_normals_and_faces() {
+        let mut obj = Vec::new();
+        write_obj(&square(), &mut obj).unwrap();
+        let obj = String::from_utf8(obj).unwrap();
+
+        let count = |prefix: &str| obj.lines().filter(|line| line.starts_with(prefix)).count();
+        assert_eq!(count("v "), 4);
+        assert_eq!(count("vn "), 4);
+        assert_eq!(count("f "), 2);
+        assert!(obj.contains("f 3//3 2//2 4//4"));
+    }
+
+    #[test]
+    fn ply_header_matches_data() {
+        let mut ply = Vec::new();
+        write_ply(&square(), &mut ply).unwrap();
+
+        let header_end = b"end_header\n";
+        let header_length = ply
+            .windows(header_end.len())
+            .position(|window| window == header_end)
+            .unwrap()
+            + header_end.len();
+        let header = std::str::from_utf8(&ply[..header_length]).unwrap();
+        assert_eq!(
+            header,
+            "ply\n\
+             format binary_little_endian 1.0\n\
+             element vertex 4\n\
+             property float x\n\
+             property float y\n\
+             property float z\n\
+             property float nx\n\
+             property float ny\n\
+             property float nz\n\
+             element face 2\n\
+             property list uchar uint vertex_indices\n\
+             end_header\n"
+        );
+        // 6 floats per vertex, a count byte and 3 indices per face
+        assert_eq!(ply.len() - header_length, 4 * 24 + 2 * 13);
+    }
+
+    #[test]
+    fn stl_header_has_triangle_count() {
+        let mut stl = Vec::new();
+        write_stl(&square(), &mut stl).unwrap();
+
+        assert!(stl[..80].iter().all(|byte| *byte == 0));
+        assert_eq!(u32::from_le_bytes(stl[80..84].try_into().unwrap()), 2);
+        assert_eq!(stl.len(), 84 + 2 * 50);
+        // normal of the first triangle
+        let normal: Vec<f32> = stl[84..96]
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        assert_eq!(normal, [0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn non_triangle_lists_are_rejected() {
+        let mesh = Mesh::new(PrimitiveTopology::LineList, RenderAssetUsages::default())
+            .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, vec![[0.0f32; 3]; 2]);
+        assert!(write_stl(&mesh, &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn glb_chunks_are_aligned() {
+        let mut world = World::new();
+        let mut isosurfaces = Assets::<Isosurface>::default();
+        let handle = isosurfaces.add(Isosurface::default());
+        world.insert_resource(isosurfaces);
+        let entity = world
+            .spawn((IsosurfaceHandle(handle), GlobalTransform::IDENTITY))
+            .id();
+
+        let mut glb = Vec::new();
+        write_glb(&world, &[entity], &mut glb).unwrap();
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(glb[offset..offset + 4].try_into().unwrap());
+
+        assert_eq!(u32_at(0), GLB_MAGIC);
+        assert_eq!(u32_at(4), 2);
+        assert_eq!(u32_at(8) as usize, glb.len());
+
+        let json_length = u32_at(12) as usize;
+        assert_eq!(json_length % 4, 0);
+        assert_eq!(u32_at(16), GLB_JSON_CHUNK);
+        let json = std::str::from_utf8(&glb[20..20 + json_length]).unwrap();
+        assert!(json.trim_end().starts_with('{') && json.trim_end().ends_with('}'));
+
+        let bin_offset = 20 + json_length;
+        let bin_length = u32_at(bin_offset) as usize;
+        assert_eq!(bin_length % 4, 0);
+        assert_eq!(u32_at(bin_offset + 4), GLB_BIN_CHUNK);
+        assert_eq!(bin_offset + 8 + bin_length, glb.len());
+        assert!(json.contains(r#""buffers":[{"byteLength":"#));
+    }
+
+    #[test]
+    fn gltf_material_has_alpha_mode_and_finite_numbers() {
+        let material = StandardMaterial {
+            metallic: f32::NAN,
+            base_color: Color::linear_rgb(f32::INFINITY, 0.0, 0.0),
+            alpha_mode: AlphaMode::Mask(0.25),
+            ..default()
+        };
+        let json = gltf_material(&material);
+
+        assert!(json.contains(r#""alphaMode":"MASK","alphaCutoff":0.25"#));
+        assert!(json.contains(r#""metallicFactor":0,"#));
+        assert!(!json.contains("NaN") && !json.contains("inf"));
+        assert_eq!(json_array(&[f32::NEG_INFINITY]), format!("[{}]", f32::MIN));
+    }
+}
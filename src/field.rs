@@ -0,0 +1,54 @@
+use bevy::prelude::*;
+
+use crate::{Isosurface, IsosurfaceBoundary};
+
+// cpu copy of `sdf` from isosurface_compute.wgsl, both have to be changed together.
+// it's used wherever the surface is needed outside of the gpu
+pub fn sdf(x: Vec3) -> f32 {
+    let torus_radius = 3.0;
+    let tube_radius = 2.0;
+
+    let q = Vec2::new(Vec2::new(x.x, x.y).length() - torus_radius, x.z);
+    q.length() - tube_radius
+}
+
+impl Isosurface {
+    // box is shrunk by half a cell, see `grid_box_sdf` in the shader
+    fn grid_box_sdf(&self, x: Vec3) -> f32 {
+        let half_extents = (self.grid_size - self.cell_size()) / 2.0;
+        let q = (x - self.grid_origin).abs() - half_extents;
+        q.max(Vec3::ZERO).length() + q.max_element().min(0.0)
+    }
+
+    // the field which is polygonized, in local space of the isosurface
+    pub fn field(&self, x: Vec3) -> f32 {
+        let value = sdf(x);
+        match self.boundary {
+            IsosurfaceBoundary::Open => value,
+            IsosurfaceBoundary::Capped => value.max(self.grid_box_sdf(x)),
+        }
+    }
+
+    // central differences, epsilon is a small fraction of the cell
+    pub fn field_gradient(&self, x: Vec3) -> Vec3 {
+        let epsilon = 0.01 * self.cell_size().min_element();
+        Vec3::new(
+            self.field(x + Vec3::X * epsilon) - self.field(x - Vec3::X * epsilon),
+            self.field(x + Vec3::Y * epsilon) - self.field(x - Vec3::Y * epsilon),
+            self.field(x + Vec3::Z * epsilon) - self.field(x - Vec3::Z * epsilon),
+        ) / (2.0 * epsilon)
+    }
+}
+
+// cpu copy of `sdf_material` from isosurface_compute.wgsl, weights of up to four
+// material layers at the point, they don't have to be normalized
+pub fn sdf_material(x: Vec3) -> Vec4 {
+    let smoothstep = |low: f32, high: f32, value: f32| {
+        let t = ((value - low) / (high - low)).clamp(0.0, 1.0);
+        t * t * (3.0 - 2.0 * t)
+    };
+    let grass = smoothstep(0.5, 1.5, x.z);
+    let dirt = smoothstep(0.5, 1.5, -x.z);
+    let rock = 1.0 - grass - dirt;
+    Vec4::new(rock, dirt, grass, 0.0)
+}
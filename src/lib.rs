@@ -1,25 +1,76 @@
+mod collider;
+mod collision;
 mod compute;
+mod export;
+mod field;
+mod material;
+mod navmesh;
+mod pathfinding;
+mod picking;
+mod polygonize;
+mod raycast;
+mod sampling;
+mod volume;
 
 use bevy::{
     asset::RenderAssetUsages,
-    ecs::system::SystemParamItem,
+    ecs::system::{SystemParam, SystemParamItem},
     prelude::*,
     render::{
-        mesh::PrimitiveTopology,
+        extract_resource::ExtractResource,
+        mesh::{MeshVertexAttribute, PrimitiveTopology, VertexAttributeValues},
+        primitives::Aabb,
         render_asset::{ExtractedAssets, PrepareAssetError, RenderAsset, RenderAssetPlugin},
-        view::{check_visibility, VisibilitySystems},
+        render_resource::VertexFormat,
+        view::{calculate_bounds, check_visibility, NoFrustumCulling, VisibilitySystems},
         Extract, Render, RenderApp, RenderSet,
     },
-    utils::{Entry, HashMap},
+    utils::{Entry, HashMap, HashSet},
 };
 
-use compute::CalculateIsosurfaceTasks;
+pub use collider::{IsosurfaceCollider, IsosurfaceColliderSettings};
+pub use collision::{IsosurfaceCollisions, IsosurfaceContact};
+pub use export::{write_glb, write_obj, write_ply, write_stl};
+pub use field::{sdf, sdf_material};
+pub use material::{IsosurfaceMaterial, IsosurfaceMaterialLayers};
+pub use navmesh::{
+    IsosurfaceNavMesh, IsosurfaceNavMeshLink, IsosurfaceNavMeshLinks, IsosurfaceNavMeshSettings,
+};
+pub use pathfinding::{IsosurfaceFreeSpace, IsosurfacePathError};
+pub use picking::IsosurfacePickingPlugin;
+pub use raycast::{IsosurfaceRayHit, IsosurfaceRaycast};
+pub use sampling::{IsosurfaceSampleDistribution, IsosurfaceSampling, IsosurfaceSurfacePoint};
+pub use volume::{IsosurfaceChunk, IsosurfaceVolume, IsosurfaceVolumeFocus};
+
+pub use compute::{IsosurfaceSdfBake, IsosurfaceSdfFormat, IsosurfaceSdfImage};
+
+use compute::{
+    CalculateIsosurfaceTask, CalculateIsosurfaceTasks, ComputeResults, ComputedBounds,
+    ComputedMesh, IsosurfaceRenderResources,
+};
 
 #[derive(Component, Clone, Debug, Default, Deref, DerefMut, Reflect, PartialEq, Eq)]
 #[reflect(Component, Default)]
 #[require(Transform, Visibility)]
 pub struct IsosurfaceHandle(pub Handle<Isosurface>);
 
+// higher priority isosurfaces are polygonized first when the frame budget is limited.
+// without this component isosurfaces have priority 0 and are ordered by camera distance
+#[derive(Component, Clone, Copy, Debug, Default, Deref, DerefMut, Reflect, PartialEq, Eq)]
+#[reflect(Component, Default)]
+pub struct IsosurfacePriority(pub i32);
+
+// limits how much polygonization work is dispatched per frame, pending tasks are spread
+// over the next frames. at least one task is dispatched every frame
+#[derive(Resource, Clone, Copy, Debug, Default, ExtractResource, Reflect)]
+#[reflect(Resource, Default)]
+pub struct IsosurfaceComputeBudget {
+    // maximum amount of grid cells including stitching aprons, see `Isosurface::grid_density`
+    pub max_cells_per_frame: Option<u64>,
+    // maximum amount of isosurfaces
+    pub max_dispatches_per_frame: Option<u32>,
+}
+
 #[derive(Default)]
 pub struct IsosurfacePlugin;
 
@@ -27,26 +78,199 @@ impl Plugin for IsosurfacePlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins(RenderAssetPlugin::<ComputeIsosurface>::default())
             .add_plugins(compute::ComputeIsosurfacePlugin)
+            .add_plugins(volume::IsosurfaceVolumePlugin)
+            .add_plugins(material::IsosurfaceMaterialPlugin)
+            .add_plugins(collider::IsosurfaceColliderPlugin)
+            .add_plugins(navmesh::IsosurfaceNavMeshPlugin)
             .init_asset::<Isosurface>()
             .add_systems(
                 PostUpdate,
                 check_visibility::<With<IsosurfaceHandle>>
                     .in_set(VisibilitySystems::CheckVisibility),
             )
-            .add_systems(PostUpdate, insert_phony_meshes)
-            .init_resource::<MeshRegistry>();
+            .add_systems(PreUpdate, (swap_computed_meshes, receive_computed_bounds))
+            // chunks spawned by volumes get their meshes in the same frame, commands are
+            // applied by the sync point between the ordered systems
+            .add_systems(
+                PostUpdate,
+                (
+                    insert_phony_meshes.after(volume::update_volume_chunks),
+                    update_mesh_registry,
+                ),
+            )
+            .add_systems(
+                PostUpdate,
+                update_isosurface_aabbs
+                    .in_set(VisibilitySystems::CalculateBounds)
+                    .after(calculate_bounds),
+            )
+            .init_resource::<MeshRegistry>()
+            .init_resource::<TightBounds>();
 
         app.sub_app_mut(RenderApp)
             .add_systems(ExtractSchedule, fill_render_mesh_registry)
             .add_systems(Render, schedule_isosurface_tasks.in_set(RenderSet::Queue))
-            .init_resource::<MeshRegistry>();
+            .init_resource::<RenderMeshRegistry>()
+            .init_resource::<PendingIsosurfaceTasks>();
+    }
+}
+
+// every isosurface owns two meshes. front one is drawn, back one is written by the compute
+// pass and swapped in only after the pass is done, so a partially written mesh is never drawn
+struct IsosurfaceMeshes {
+    front: Handle<Mesh>,
+    back: Handle<Mesh>,
+    // buffers of the back mesh are allocated for this layout and amount of cells
+    vertex_layout: VertexLayout,
+    extent_cells_count: u64,
+    // the front mesh was allocated for the previous layout or grid, it's still drawn until
+    // the back one is computed and replaced after the swap
+    outdated_front: bool,
+}
+
+impl IsosurfaceMeshes {
+    fn new(mesh_server: &mut Assets<Mesh>, isosurface: &Isosurface) -> Self {
+        let vertex_layout = isosurface.vertex_layout();
+        Self {
+            front: mesh_server.add(vertex_layout.phony_mesh()),
+            back: mesh_server.add(vertex_layout.phony_mesh()),
+            vertex_layout,
+            extent_cells_count: isosurface.extent_cells_count(),
+            outdated_front: false,
+        }
     }
 }
 
+// attributes of generated vertices. they are interleaved in the order of attribute ids,
+// the same way `Mesh` does it, position and normal are always there
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct VertexLayout {
+    pub uv: bool,
+    pub tangent: bool,
+    pub color: bool,
+}
+
+impl VertexLayout {
+    // bits of the shader's vertex_attributes
+    pub const UV_BIT: u32 = 1;
+    pub const TANGENT_BIT: u32 = 2;
+    pub const COLOR_BIT: u32 = 4;
+
+    pub fn attributes(&self) -> Vec<MeshVertexAttribute> {
+        let mut attributes = vec![Mesh::ATTRIBUTE_POSITION, Mesh::ATTRIBUTE_NORMAL];
+        if self.uv {
+            attributes.push(Mesh::ATTRIBUTE_UV_0);
+        }
+        if self.tangent {
+            attributes.push(Mesh::ATTRIBUTE_TANGENT);
+        }
+        if self.color {
+            attributes.push(Mesh::ATTRIBUTE_COLOR);
+        }
+        attributes
+    }
+
+    pub fn vertex_size(&self) -> u64 {
+        self.attributes()
+            .iter()
+            .map(|attribute| attribute.format.size())
+            .sum()
+    }
+
+    pub fn bits(&self) -> u32 {
+        let mut bits = 0;
+        if self.uv {
+            bits |= Self::UV_BIT;
+        }
+        if self.tangent {
+            bits |= Self::TANGENT_BIT;
+        }
+        if self.color {
+            bits |= Self::COLOR_BIT;
+        }
+        bits
+    }
+
+    fn phony_mesh(&self) -> Mesh {
+        let mut mesh = Mesh::new(
+            PrimitiveTopology::TriangleList,
+            RenderAssetUsages::RENDER_WORLD,
+        );
+        mesh.custom_allocation = true;
+        for attribute in self.attributes() {
+            let values = match attribute.format {
+                VertexFormat::Float32x2 => VertexAttributeValues::Float32x2(Vec::new()),
+                VertexFormat::Float32x3 => VertexAttributeValues::Float32x3(Vec::new()),
+                VertexFormat::Float32x4 => VertexAttributeValues::Float32x4(Vec::new()),
+                format => unreachable!("unexpected isosurface vertex format {format:?}"),
+            };
+            mesh.insert_attribute(attribute, values);
+        }
+        mesh
+    }
+}
+
+// entities with settings `S` whose isosurface has to be processed again on the cpu, because
+// the asset, the handle or the settings changed since the system last ran. an isosurface is
+// the unit of regeneration, so for `IsosurfaceVolume` only changed chunks are redone,
+// but always whole chunks
+#[derive(SystemParam)]
+pub(crate) struct ChangedIsosurfaces<'w, 's, S: Component> {
+    asset_events: EventReader<'w, 's, AssetEvent<Isosurface>>,
+    isosurfaces: Res<'w, Assets<Isosurface>>,
+    entities: Query<'w, 's, (Entity, Ref<'static, IsosurfaceHandle>, Ref<'static, S>)>,
+}
+
+impl<S: Component> ChangedIsosurfaces<'_, '_, S> {
+    pub fn read(&mut self) -> Vec<(Entity, AssetId<Isosurface>, &Isosurface, &S)> {
+        let modified: HashSet<AssetId<Isosurface>> = self
+            .asset_events
+            .read()
+            .filter_map(|event| match event {
+                AssetEvent::Added { id }
+                | AssetEvent::Modified { id }
+                | AssetEvent::LoadedWithDependencies { id } => Some(*id),
+                _ => None,
+            })
+            .collect();
+
+        self.entities
+            .iter()
+            .filter(|(_, isosurface_handle, settings)| {
+                isosurface_handle.is_changed()
+                    || settings.is_changed()
+                    || modified.contains(&isosurface_handle.id())
+            })
+            .filter_map(|(entity, isosurface_handle, settings)| {
+                let isosurface = self.isosurfaces.get(&isosurface_handle.0)?;
+                Some((
+                    entity,
+                    isosurface_handle.id(),
+                    isosurface,
+                    settings.into_inner(),
+                ))
+            })
+            .collect()
+    }
+}
+
+#[derive(Resource, Default, DerefMut, Deref)]
+struct MeshRegistry(HashMap<AssetId<Isosurface>, IsosurfaceMeshes>);
+
+// back meshes of isosurfaces, the ones compute tasks write into
 #[derive(Resource, Default, DerefMut, Deref)]
-struct MeshRegistry(HashMap<AssetId<Isosurface>, AssetId<Mesh>>);
+struct RenderMeshRegistry(HashMap<AssetId<Isosurface>, AssetId<Mesh>>);
 
-#[derive(Asset, Clone, Reflect)]
+// isosurfaces extracted before their meshes were registered, tasks wait for the meshes
+#[derive(Resource, Default, DerefMut, Deref)]
+struct PendingIsosurfaceTasks(HashSet<AssetId<Isosurface>>);
+
+// bounds of generated vertices read back from the gpu with the mesh they were read from.
+// reading back takes a few frames, so they are used only while that mesh is drawn
+#[derive(Resource, Default, DerefMut, Deref)]
+struct TightBounds(HashMap<AssetId<Isosurface>, (AssetId<Mesh>, Aabb)>);
+
+#[derive(Asset, Clone, PartialEq, Reflect)]
 pub struct Isosurface {
     pub grid_size: Vec3,
     pub grid_origin: Vec3,
@@ -57,15 +281,245 @@ pub struct Isosurface {
     // y = 8 * grid_density.y
     // z = 8 * grid_density.z
     pub grid_density: UVec3,
+    // by default isosurface entities are culled using the whole grid box.
+    // when enabled, bounds of the generated vertices are read back from the gpu and used instead
+    pub tight_bounds: bool,
+    // faces of the grid box shared with neighbouring grids, see `IsosurfaceStitching`
+    pub stitching: IsosurfaceStitching,
+    pub boundary: IsosurfaceBoundary,
+    pub normals: IsosurfaceNormals,
+    // moves vertices onto the surface, averaged edge intersections are slightly off it
+    pub projection: Option<IsosurfaceProjection>,
+    pub ordering: IsosurfaceOrdering,
+    // generates `Mesh::ATTRIBUTE_UV_0`, so textures of `StandardMaterial` can be used
+    pub uvs: Option<IsosurfaceUvs>,
+    // writes weights of `sdf_material` from the shader to `Mesh::ATTRIBUTE_COLOR`,
+    // one channel per material layer, see `IsosurfaceMaterial`
+    pub materials: bool,
+    // darkens vertices in crevices. the occlusion is written to `Mesh::ATTRIBUTE_COLOR`,
+    // as gray for `StandardMaterial` or multiplied into the weights with `materials`
+    pub ambient_occlusion: Option<IsosurfaceAmbientOcclusion>,
     pub asset_usage: RenderAssetUsages,
 }
 
+// box projection, the plane is picked by the axis closest to the normal
+#[derive(Clone, Copy, Debug, PartialEq, Reflect)]
+pub struct IsosurfaceUvs {
+    // size of one texture repeat in local space of the isosurface
+    pub scale: f32,
+    // generates `Mesh::ATTRIBUTE_TANGENT` following the projection, needed for normal maps
+    pub tangents: bool,
+}
+
+impl Default for IsosurfaceUvs {
+    fn default() -> Self {
+        Self {
+            scale: 1.0,
+            tangents: false,
+        }
+    }
+}
+
+// distance field ambient occlusion, the field is sampled along the normal of the vertex
+// and compared with the distance from the vertex. closer samples weigh more
+#[derive(Clone, Copy, Debug, PartialEq, Reflect)]
+pub struct IsosurfaceAmbientOcclusion {
+    pub samples: u32,
+    // distance between samples in local space of the isosurface
+    pub distance: f32,
+    // 0 disables darkening, 1 makes fully occluded vertices black
+    pub strength: f32,
+}
+
+impl Default for IsosurfaceAmbientOcclusion {
+    fn default() -> Self {
+        Self {
+            samples: 5,
+            distance: 0.25,
+            strength: 1.0,
+        }
+    }
+}
+
+// order of vertices and quads in the generated buffers
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Reflect)]
+pub enum IsosurfaceOrdering {
+    // slots are taken from atomic counters, order differs between runs and gpus
+    #[default]
+    Atomic,
+    // slots are taken from prefix sums over cells, identical inputs always produce
+    // byte-identical buffers. costs two more dispatches and a buffer of 2 u32 per cell
+    Deterministic,
+}
+
+// newton iterations along the field gradient, vertices never leave their cell
+#[derive(Clone, Copy, Debug, PartialEq, Reflect)]
+pub struct IsosurfaceProjection {
+    pub iterations: u32,
+    // stops once the absolute value of the field at the vertex is below it
+    pub tolerance: f32,
+}
+
+impl Default for IsosurfaceProjection {
+    fn default() -> Self {
+        Self {
+            iterations: 4,
+            tolerance: 1e-4,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Reflect)]
+pub enum IsosurfaceNormals {
+    // estimated from the field at the 8 corners of the cell, cheap but faceted
+    #[default]
+    Cell,
+    // gradient of the field at the vertex position, 6 more field samples per vertex.
+    // smooth shading and correct normals near thin features
+    Gradient,
+}
+
+// what happens to the surface where it leaves the grid box
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Reflect)]
+pub enum IsosurfaceBoundary {
+    // the surface is cut off, the mesh is open at the box faces
+    #[default]
+    Open,
+    // everything outside of the box is treated as outside of the surface, so the mesh is
+    // closed with caps along the box faces. caps are generated half a cell inside of the box.
+    // not meant to be combined with stitching, stitched faces get capped too
+    Capped,
+}
+
+// by default quads crossing faces of the grid box are not generated, so isosurfaces tiled
+// with touching grid boxes leave gaps between them. a stitched face polygonizes one extra
+// layer of cells outside of the box (apron) and generates quads across the face.
+// every shared face has to be stitched by exactly one of the two grids.
+//
+// the value is how many times the neighbour's `grid_density` is halved compared to this grid,
+// apron cells take vertices of the neighbour's larger cells, so grids of different resolution
+// meet without cracks. the finer grid is the one which has to stitch such face.
+//
+// edges of the box where two stitched faces meet are shared by four grids, quads along them
+// are generated only by the grid which stitches the edge, so exactly one of the four has to.
+// the value is for the diagonal neighbour across the edge, it needs both faces stitched
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Reflect)]
+pub struct IsosurfaceStitching {
+    // faces at the negative end of x, y and z axes
+    pub negative: [Option<u32>; 3],
+    // faces at the positive end of x, y and z axes
+    pub positive: [Option<u32>; 3],
+    // edges along x, y and z axes. the other two axes follow the edge axis cyclically,
+    // bit 0 of the index is set for edges at the positive end of the first one
+    // and bit 1 for edges at the positive end of the second one
+    pub edges: [[Option<u32>; 4]; 3],
+}
+
+impl IsosurfaceStitching {
+    // grids of the same resolution, each one stitches faces towards its negative neighbours
+    pub const NEGATIVE_FACES: Self = Self {
+        negative: [Some(0); 3],
+        positive: [None; 3],
+        edges: [[Some(0), None, None, None]; 3],
+    };
+
+    pub fn apron_min(&self) -> UVec3 {
+        UVec3::from_array(self.negative.map(|face| face.is_some() as u32))
+    }
+
+    pub fn apron_max(&self) -> UVec3 {
+        UVec3::from_array(self.positive.map(|face| face.is_some() as u32))
+    }
+
+    // size of apron cells in cells of this grid
+    pub fn apron_scale_min(&self) -> UVec3 {
+        UVec3::from_array(self.negative.map(|face| 1 << face.unwrap_or(0)))
+    }
+
+    pub fn apron_scale_max(&self) -> UVec3 {
+        UVec3::from_array(self.positive.map(|face| 1 << face.unwrap_or(0)))
+    }
+
+    // size of apron cells of the diagonal neighbours, 0 where the edge isn't stitched
+    pub fn edge_scales(&self) -> [UVec4; 3] {
+        self.edges
+            .map(|edges| UVec4::from_array(edges.map(|edge| edge.map_or(0, |edge| 1 << edge))))
+    }
+
+    // largest apron cells on the negative and positive ends of every axis
+    fn apron_extent(&self) -> (UVec3, UVec3) {
+        let mut min = self.apron_min() * self.apron_scale_min();
+        let mut max = self.apron_max() * self.apron_scale_max();
+        for (axis, edges) in self.edges.iter().enumerate() {
+            let others = [(axis + 1) % 3, (axis + 2) % 3];
+            for (index, edge) in edges.iter().enumerate() {
+                let Some(edge) = edge else {
+                    continue;
+                };
+                for (bit, other) in others.into_iter().enumerate() {
+                    let side = if index & (1 << bit) != 0 {
+                        &mut max[other]
+                    } else {
+                        &mut min[other]
+                    };
+                    *side = (*side).max(1 << edge);
+                }
+            }
+        }
+        (min, max)
+    }
+}
+
+impl Isosurface {
+    pub(crate) fn vertex_layout(&self) -> VertexLayout {
+        VertexLayout {
+            uv: self.uvs.is_some(),
+            tangent: self.uvs.is_some_and(|uvs| uvs.tangents),
+            color: self.materials || self.ambient_occlusion.is_some(),
+        }
+    }
+
+    pub fn cells(&self) -> UVec3 {
+        self.grid_density * 8
+    }
+
+    pub fn cell_size(&self) -> Vec3 {
+        self.grid_size / self.cells().as_vec3()
+    }
+
+    // amount of cells polygonized, including apron layers
+    pub(crate) fn extent_cells_count(&self) -> u64 {
+        let extent = self.cells() + self.stitching.apron_min() + self.stitching.apron_max();
+        extent.as_u64vec3().element_product()
+    }
+
+    // conservative bounds, everything generated is inside the grid box and the apron
+    pub fn grid_aabb(&self) -> Aabb {
+        let half_size = self.grid_size / 2.0;
+        let (apron_min, apron_max) = self.stitching.apron_extent();
+        let (apron_min, apron_max) = (apron_min.as_vec3(), apron_max.as_vec3());
+        Aabb::from_min_max(
+            self.grid_origin - half_size - apron_min * self.cell_size(),
+            self.grid_origin + half_size + apron_max * self.cell_size(),
+        )
+    }
+}
+
 impl Default for Isosurface {
     fn default() -> Self {
         Self {
             grid_size: Vec3::splat(10.0),
             grid_origin: Vec3::ZERO,
             grid_density: UVec3::splat(1),
+            tight_bounds: false,
+            stitching: IsosurfaceStitching::default(),
+            boundary: IsosurfaceBoundary::Open,
+            normals: IsosurfaceNormals::Cell,
+            projection: None,
+            ordering: IsosurfaceOrdering::Atomic,
+            uvs: None,
+            materials: false,
+            ambient_occlusion: None,
             asset_usage: RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD,
         }
     }
@@ -75,6 +529,34 @@ pub struct ComputeIsosurface {
     pub grid_size: Vec3,
     pub grid_origin: Vec3,
     pub grid_density: UVec3,
+    pub tight_bounds: bool,
+    pub stitching: IsosurfaceStitching,
+    pub boundary: IsosurfaceBoundary,
+    pub normals: IsosurfaceNormals,
+    pub projection: Option<IsosurfaceProjection>,
+    pub ordering: IsosurfaceOrdering,
+    pub uvs: Option<IsosurfaceUvs>,
+    pub materials: bool,
+    pub ambient_occlusion: Option<IsosurfaceAmbientOcclusion>,
+    pub(crate) vertex_layout: VertexLayout,
+}
+
+impl ComputeIsosurface {
+    pub fn cells(&self) -> UVec3 {
+        self.grid_density * 8
+    }
+
+    // amount of cells polygonized, including apron layers
+    pub fn extent_cells_count(&self) -> u64 {
+        let extent = self.cells() + self.stitching.apron_min() + self.stitching.apron_max();
+        extent.as_u64vec3().element_product()
+    }
+
+    // one more workgroup is needed on axes with apron layers
+    pub fn workgroups(&self) -> UVec3 {
+        let apron = self.stitching.apron_min() + self.stitching.apron_max();
+        self.grid_density + apron.min(UVec3::ONE)
+    }
 }
 
 impl RenderAsset for ComputeIsosurface {
@@ -90,6 +572,16 @@ impl RenderAsset for ComputeIsosurface {
             grid_size: source_asset.grid_size,
             grid_origin: source_asset.grid_origin,
             grid_density: source_asset.grid_density,
+            tight_bounds: source_asset.tight_bounds,
+            stitching: source_asset.stitching,
+            boundary: source_asset.boundary,
+            normals: source_asset.normals,
+            projection: source_asset.projection,
+            ordering: source_asset.ordering,
+            uvs: source_asset.uvs,
+            materials: source_asset.materials,
+            ambient_occlusion: source_asset.ambient_occlusion,
+            vertex_layout: source_asset.vertex_layout(),
         })
     }
 
@@ -100,14 +592,12 @@ impl RenderAsset for ComputeIsosurface {
 }
 
 fn fill_render_mesh_registry(
-    mut mesh_registry: ResMut<MeshRegistry>,
-    isosurfaces: Extract<Query<(&IsosurfaceHandle, &Mesh3d)>>,
+    mut render_mesh_registry: ResMut<RenderMeshRegistry>,
+    mesh_registry: Extract<Res<MeshRegistry>>,
 ) {
-    for (isosurface_handle, mesh_handle) in isosurfaces.iter() {
-        if mesh_registry.contains_key(&isosurface_handle.id()) {
-            continue;
-        }
-        mesh_registry.insert(isosurface_handle.id(), mesh_handle.id());
+    render_mesh_registry.clear();
+    for (isosurface_id, meshes) in mesh_registry.iter() {
+        render_mesh_registry.insert(*isosurface_id, meshes.back.id());
     }
 }
 
@@ -115,36 +605,174 @@ fn insert_phony_meshes(
     mut commands: Commands,
     mut mesh_server: ResMut<Assets<Mesh>>,
     mut registry: ResMut<MeshRegistry>,
+    isosurface_assets: Res<Assets<Isosurface>>,
     isosurfaces: Query<(Entity, &IsosurfaceHandle), Without<Mesh3d>>,
 ) {
     for (entity, isosurface_handle) in isosurfaces.iter() {
         let handle = match registry.entry(isosurface_handle.id()) {
-            Entry::Occupied(entry) => mesh_server.get_strong_handle(*entry.get()).unwrap(),
+            Entry::Occupied(entry) => entry.get().front.clone(),
             Entry::Vacant(entry) => {
-                let mut mesh = Mesh::new(
-                    PrimitiveTopology::TriangleList,
-                    RenderAssetUsages::RENDER_WORLD,
-                );
-                mesh.custom_allocation = true;
-                mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, Vec::<[f32; 3]>::new());
-                mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, Vec::<[f32; 3]>::new());
-                let handle = mesh_server.add(mesh);
-                entry.insert(handle.id());
-                handle
+                // vertex layout is not known until the asset is there
+                let Some(isosurface) = isosurface_assets.get(isosurface_handle.id()) else {
+                    continue;
+                };
+                let meshes = entry.insert(IsosurfaceMeshes::new(&mut mesh_server, isosurface));
+                meshes.front.clone()
             }
         };
         commands.entity(entity).insert(Mesh3d(handle));
     }
 }
 
+// chunks of volumes come and go, meshes of removed isosurfaces have to go with them.
+// meshes allocated for another vertex layout or grid can't be reused. the back mesh is
+// replaced right away, the front one keeps being drawn until the next swap
+fn update_mesh_registry(
+    mut asset_events: EventReader<AssetEvent<Isosurface>>,
+    mut mesh_server: ResMut<Assets<Mesh>>,
+    mut registry: ResMut<MeshRegistry>,
+    isosurface_assets: Res<Assets<Isosurface>>,
+) {
+    for event in asset_events.read() {
+        match event {
+            AssetEvent::Removed { id } => {
+                registry.remove(id);
+            }
+            AssetEvent::Modified { id } => {
+                let (Some(meshes), Some(isosurface)) =
+                    (registry.get_mut(id), isosurface_assets.get(*id))
+                else {
+                    continue;
+                };
+                if meshes.vertex_layout == isosurface.vertex_layout()
+                    && meshes.extent_cells_count == isosurface.extent_cells_count()
+                {
+                    continue;
+                }
+                meshes.vertex_layout = isosurface.vertex_layout();
+                meshes.extent_cells_count = isosurface.extent_cells_count();
+                meshes.back = mesh_server.add(meshes.vertex_layout.phony_mesh());
+                meshes.outdated_front = true;
+            }
+            _ => {}
+        }
+    }
+}
+
+fn swap_computed_meshes(
+    computed_meshes: Res<ComputeResults<ComputedMesh>>,
+    mut mesh_server: ResMut<Assets<Mesh>>,
+    mut registry: ResMut<MeshRegistry>,
+    mut isosurfaces: Query<(&IsosurfaceHandle, &mut Mesh3d)>,
+) {
+    let computed = computed_meshes.drain();
+    if computed.is_empty() {
+        return;
+    }
+    for ComputedMesh {
+        isosurface_id,
+        mesh_id,
+    } in computed
+    {
+        let Some(meshes) = registry.get_mut(&isosurface_id) else {
+            continue;
+        };
+        // the same mesh could be computed several times before the swap got here
+        if meshes.back.id() != mesh_id {
+            continue;
+        }
+        std::mem::swap(&mut meshes.front, &mut meshes.back);
+        if meshes.outdated_front {
+            meshes.back = mesh_server.add(meshes.vertex_layout.phony_mesh());
+            meshes.outdated_front = false;
+        }
+        for (isosurface_handle, mut mesh_handle) in isosurfaces.iter_mut() {
+            if isosurface_handle.id() == isosurface_id {
+                mesh_handle.0 = meshes.front.clone();
+            }
+        }
+    }
+}
+
+fn receive_computed_bounds(
+    computed_bounds: Res<ComputeResults<ComputedBounds>>,
+    mut asset_events: EventReader<AssetEvent<Isosurface>>,
+    mut tight_bounds: ResMut<TightBounds>,
+) {
+    // bounds of the previous version of isosurface are no longer valid
+    for event in asset_events.read() {
+        if let AssetEvent::Modified { id } | AssetEvent::Removed { id } = event {
+            tight_bounds.remove(id);
+        }
+    }
+    for ComputedBounds {
+        isosurface_id,
+        mesh_id,
+        aabb,
+    } in computed_bounds.drain()
+    {
+        tight_bounds.insert(isosurface_id, (mesh_id, aabb));
+    }
+}
+
+fn update_isosurface_aabbs(
+    mut commands: Commands,
+    isosurface_assets: Res<Assets<Isosurface>>,
+    tight_bounds: Res<TightBounds>,
+    mut isosurfaces: Query<
+        (
+            Entity,
+            &IsosurfaceHandle,
+            Option<&Mesh3d>,
+            Option<&mut Aabb>,
+        ),
+        Without<NoFrustumCulling>,
+    >,
+) {
+    for (entity, isosurface_handle, mesh, aabb) in isosurfaces.iter_mut() {
+        let Some(isosurface) = isosurface_assets.get(isosurface_handle.id()) else {
+            continue;
+        };
+        let new_aabb = match tight_bounds.get(&isosurface_handle.id()) {
+            Some((mesh_id, tight_aabb))
+                if isosurface.tight_bounds && mesh.is_some_and(|mesh| mesh.id() == *mesh_id) =>
+            {
+                *tight_aabb
+            }
+            _ => isosurface.grid_aabb(),
+        };
+        match aabb {
+            Some(mut aabb) => {
+                if *aabb != new_aabb {
+                    *aabb = new_aabb;
+                }
+            }
+            None => {
+                commands.entity(entity).insert(new_aabb);
+            }
+        }
+    }
+}
+
 fn schedule_isosurface_tasks(
     extracted_meshes: Res<ExtractedAssets<ComputeIsosurface>>,
-    mesh_registry: Res<MeshRegistry>,
+    mesh_registry: Res<RenderMeshRegistry>,
     mut tasks: ResMut<CalculateIsosurfaceTasks>,
+    mut pending: ResMut<PendingIsosurfaceTasks>,
+    mut render_resources: IsosurfaceRenderResources,
 ) {
-    for id in extracted_meshes.added.iter() {
-        let mesh_id = mesh_registry.get(id).unwrap();
-        info!("scheduling task for isosurface {}", id);
-        tasks.insert(*id, *mesh_id);
+    for id in extracted_meshes.removed.iter() {
+        tasks.remove(id);
+        pending.remove(id);
+        render_resources.remove(id);
     }
+    pending.extend(extracted_meshes.added.iter().copied());
+    pending.retain(|id| {
+        if !mesh_registry.contains_key(id) {
+            return true;
+        }
+        info!("scheduling task for isosurface {}", id);
+        tasks.insert(*id, CalculateIsosurfaceTask::default());
+        false
+    });
 }
@@ -0,0 +1,49 @@
+use bevy::{
+    pbr::{ExtendedMaterial, MaterialExtension},
+    prelude::*,
+    render::render_resource::{AsBindGroup, ShaderRef},
+};
+
+pub struct IsosurfaceMaterialPlugin;
+
+impl Plugin for IsosurfaceMaterialPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(MaterialPlugin::<IsosurfaceMaterial>::default())
+            .register_type::<IsosurfaceMaterialLayers>();
+    }
+}
+
+// `StandardMaterial` with the base color replaced by a blend of up to four layers.
+// layers are weighted by `Mesh::ATTRIBUTE_COLOR`, so it's meant for isosurfaces
+// with `Isosurface::materials` enabled. everything else comes from the base material
+pub type IsosurfaceMaterial = ExtendedMaterial<StandardMaterial, IsosurfaceMaterialLayers>;
+
+// textures are sampled with `Mesh::ATTRIBUTE_UV_0`, without `Isosurface::uvs` only
+// colors are blended. texture of a layer is multiplied by its color
+#[derive(Asset, AsBindGroup, Clone, Debug, Default, Reflect)]
+pub struct IsosurfaceMaterialLayers {
+    #[uniform(100)]
+    pub colors: [LinearRgba; 4],
+    #[texture(101)]
+    #[sampler(102)]
+    pub texture_0: Option<Handle<Image>>,
+    #[texture(103)]
+    #[sampler(104)]
+    pub texture_1: Option<Handle<Image>>,
+    #[texture(105)]
+    #[sampler(106)]
+    pub texture_2: Option<Handle<Image>>,
+    #[texture(107)]
+    #[sampler(108)]
+    pub texture_3: Option<Handle<Image>>,
+}
+
+impl MaterialExtension for IsosurfaceMaterialLayers {
+    fn fragment_shader() -> ShaderRef {
+        "isosurface_material.wgsl".into()
+    }
+
+    fn deferred_fragment_shader() -> ShaderRef {
+        "isosurface_material.wgsl".into()
+    }
+}
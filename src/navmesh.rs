@@ -0,0 +1,328 @@
+use bevy::{
+    prelude::*,
+    tasks::{block_on, futures_lite::future, AsyncComputeTaskPool, Task},
+    utils::{HashMap, HashSet},
+};
+
+use crate::{
+    collider::welded_triangles,
+    volume::{copy_volume_component, update_volume_chunks},
+    ChangedIsosurfaces, Isosurface, IsosurfaceChunk, IsosurfaceVolume,
+};
+
+pub struct IsosurfaceNavMeshPlugin;
+
+impl Plugin for IsosurfaceNavMeshPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            PostUpdate,
+            (
+                copy_volume_component::<IsosurfaceNavMeshSettings>.after(update_volume_chunks),
+                spawn_nav_mesh_tasks,
+                receive_nav_meshes,
+                update_volume_nav_mesh_links,
+            )
+                .chain(),
+        )
+        .register_type::<IsosurfaceNavMeshSettings>();
+    }
+}
+
+// opt-in, `IsosurfaceNavMesh` is inserted next to it and regenerated whenever the isosurface
+// changes. it's generated in the background, the previous navmesh stays until the new one
+// is done. everything is in local space of the isosurface.
+// settings on `IsosurfaceVolume` are copied to its chunks. every chunk has its own
+// isosurface and navmesh, so only changed chunks are regenerated. navmeshes of chunks
+// are connected by `IsosurfaceNavMeshLinks` of the volume
+#[derive(Component, Clone, Copy, Debug, Reflect, PartialEq)]
+#[reflect(Component, Default)]
+pub struct IsosurfaceNavMeshSettings {
+    pub up: Dir3,
+    // steepest walkable slope in radians
+    pub max_slope: f32,
+    pub agent_radius: f32,
+    pub agent_height: f32,
+}
+
+impl Default for IsosurfaceNavMeshSettings {
+    fn default() -> Self {
+        Self {
+            up: Dir3::Y,
+            max_slope: 45f32.to_radians(),
+            agent_radius: 0.5,
+            agent_height: 2.0,
+        }
+    }
+}
+
+// walkable triangles of the surface
+#[derive(Component, Clone, Debug, Default)]
+pub struct IsosurfaceNavMesh {
+    pub vertices: Vec<Vec3>,
+    pub triangles: Vec<[u32; 3]>,
+    // triangles sharing the edge opposite to every vertex of a triangle
+    pub neighbours: Vec<[Option<u32>; 3]>,
+}
+
+impl IsosurfaceNavMesh {
+    pub fn new(isosurface: &Isosurface, settings: &IsosurfaceNavMeshSettings) -> Self {
+        let (vertices, triangles) = welded_triangles(isosurface);
+        let min_up = settings.max_slope.cos();
+        let walkable: Vec<[u32; 3]> = triangles
+            .into_iter()
+            .filter(|triangle| {
+                let [a, b, c] = triangle.map(|index| vertices[index as usize]);
+                let Some(normal) = (b - a).cross(c - a).try_normalize() else {
+                    return false;
+                };
+                // winding doesn't matter, the field points out of the surface
+                let center = (a + b + c) / 3.0;
+                let normal = normal * normal.dot(isosurface.field_gradient(center)).signum();
+                normal.dot(*settings.up) >= min_up && has_clearance(isosurface, center, settings)
+            })
+            .collect();
+
+        // only vertices of walkable triangles are kept
+        let mut remap = HashMap::new();
+        let mut nav_vertices = Vec::new();
+        let triangles: Vec<[u32; 3]> = walkable
+            .iter()
+            .map(|triangle| {
+                triangle.map(|index| {
+                    *remap.entry(index).or_insert_with(|| {
+                        nav_vertices.push(vertices[index as usize]);
+                        nav_vertices.len() as u32 - 1
+                    })
+                })
+            })
+            .collect();
+
+        let neighbours = triangle_neighbours(&triangles);
+        Self {
+            vertices: nav_vertices,
+            triangles,
+            neighbours,
+        }
+    }
+}
+
+// walkable triangles of neighbouring chunks of a volume which share an edge, triangles
+// along chunk borders have no `IsosurfaceNavMesh::neighbours` there. inserted on the volume
+// and rebuilt whenever navmeshes of its chunks change, every pair is listed once
+#[derive(Component, Clone, Debug, Default)]
+pub struct IsosurfaceNavMeshLinks {
+    pub links: Vec<IsosurfaceNavMeshLink>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IsosurfaceNavMeshLink {
+    pub chunk: Entity,
+    pub triangle: u32,
+    pub other_chunk: Entity,
+    pub other_triangle: u32,
+}
+
+// the agent is a capsule standing on the point, the field around it has to be at least
+// its radius. a small tolerance lets it stand on flat ground touching the capsule
+fn has_clearance(
+    isosurface: &Isosurface,
+    point: Vec3,
+    settings: &IsosurfaceNavMeshSettings,
+) -> bool {
+    let radius = settings.agent_radius;
+    let bottom = point + *settings.up * radius;
+    let top = point + *settings.up * (settings.agent_height - radius).max(radius);
+    let (_, value) = isosurface.segment_deepest_point(bottom, top);
+    value >= radius * 0.9
+}
+
+fn triangle_neighbours(triangles: &[[u32; 3]]) -> Vec<[Option<u32>; 3]> {
+    let mut edges: HashMap<(u32, u32), Vec<u32>> = HashMap::new();
+    for (triangle_index, triangle) in triangles.iter().enumerate() {
+        for i in 0..3 {
+            let (a, b) = (triangle[(i + 1) % 3], triangle[(i + 2) % 3]);
+            edges
+                .entry((a.min(b), a.max(b)))
+                .or_default()
+                .push(triangle_index as u32);
+        }
+    }
+    triangles
+        .iter()
+        .enumerate()
+        .map(|(triangle_index, triangle)| {
+            std::array::from_fn(|i| {
+                let (a, b) = (triangle[(i + 1) % 3], triangle[(i + 2) % 3]);
+                edges[&(a.min(b), a.max(b))]
+                    .iter()
+                    .copied()
+                    .find(|other| *other != triangle_index as u32)
+            })
+        })
+        .collect()
+}
+
+// running generation, replacing it drops the outdated task
+#[derive(Component)]
+struct NavMeshTask(Task<IsosurfaceNavMesh>);
+
+fn spawn_nav_mesh_tasks(
+    mut commands: Commands,
+    mut changed: ChangedIsosurfaces<IsosurfaceNavMeshSettings>,
+) {
+    let task_pool = AsyncComputeTaskPool::get();
+    for (entity, _, isosurface, settings) in changed.read() {
+        let isosurface = isosurface.clone();
+        let settings = *settings;
+        let task = task_pool.spawn(async move { IsosurfaceNavMesh::new(&isosurface, &settings) });
+        commands.entity(entity).insert(NavMeshTask(task));
+    }
+}
+
+fn receive_nav_meshes(mut commands: Commands, mut tasks: Query<(Entity, &mut NavMeshTask)>) {
+    for (entity, mut task) in tasks.iter_mut() {
+        if let Some(nav_mesh) = block_on(future::poll_once(&mut task.0)) {
+            commands
+                .entity(entity)
+                .insert(nav_mesh)
+                .remove::<NavMeshTask>();
+        }
+    }
+}
+
+// chunks share the local space of the volume, vertices of the same cell computed by both
+// chunks of a border differ only by rounding, so edges are matched with a tolerance
+fn update_volume_nav_mesh_links(
+    mut commands: Commands,
+    volumes: Query<&IsosurfaceVolume>,
+    chunks: Query<(Entity, &IsosurfaceChunk, Ref<IsosurfaceNavMesh>)>,
+    mut removed: RemovedComponents<IsosurfaceNavMesh>,
+) {
+    let mut changed_volumes: HashSet<Entity> = chunks
+        .iter()
+        .filter(|(_, _, nav_mesh)| nav_mesh.is_changed())
+        .map(|(_, chunk, _)| chunk.volume)
+        .collect();
+    // the volume of a despawned chunk is unknown, all of them are rebuilt
+    if removed.read().count() > 0 {
+        changed_volumes.extend(chunks.iter().map(|(_, chunk, _)| chunk.volume));
+    }
+
+    for volume_entity in changed_volumes {
+        let Ok(volume) = volumes.get(volume_entity) else {
+            continue;
+        };
+        let cell_size = volume.chunk_size / (volume.chunk_density.max(UVec3::ONE) * 8).as_vec3();
+        let volume_chunks: Vec<(Entity, &IsosurfaceNavMesh)> = chunks
+            .iter()
+            .filter(|(_, chunk, _)| chunk.volume == volume_entity)
+            .map(|(entity, _, nav_mesh)| (entity, nav_mesh.into_inner()))
+            .collect();
+        commands
+            .entity(volume_entity)
+            .insert(IsosurfaceNavMeshLinks {
+                links: nav_mesh_links(&volume_chunks, cell_size.min_element()),
+            });
+    }
+}
+
+fn nav_mesh_links(
+    chunks: &[(Entity, &IsosurfaceNavMesh)],
+    cell_size: f32,
+) -> Vec<IsosurfaceNavMeshLink> {
+    let tolerance = 1e-3 * cell_size;
+    let bucket = |point: Vec3| (point / cell_size).floor().as_ivec3();
+
+    // edges without a neighbour inside of their own navmesh, bucketed by their midpoint
+    let mut border_edges = Vec::new();
+    let mut buckets: HashMap<IVec3, Vec<usize>> = HashMap::new();
+    for (chunk_index, (_, nav_mesh)) in chunks.iter().enumerate() {
+        for (triangle_index, triangle) in nav_mesh.triangles.iter().enumerate() {
+            for i in 0..3 {
+                if nav_mesh.neighbours[triangle_index][i].is_some() {
+                    continue;
+                }
+                let a = nav_mesh.vertices[triangle[(i + 1) % 3] as usize];
+                let b = nav_mesh.vertices[triangle[(i + 2) % 3] as usize];
+                buckets
+                    .entry(bucket((a + b) / 2.0))
+                    .or_default()
+                    .push(border_edges.len());
+                border_edges.push((chunk_index, triangle_index as u32, a, b));
+            }
+        }
+    }
+
+    let mut links = Vec::new();
+    for (edge_index, &(chunk_index, triangle, a, b)) in border_edges.iter().enumerate() {
+        let edge_bucket = bucket((a + b) / 2.0);
+        for z in -1..=1 {
+            for y in -1..=1 {
+                for x in -1..=1 {
+                    let Some(others) = buckets.get(&(edge_bucket + IVec3::new(x, y, z))) else {
+                        continue;
+                    };
+                    for &other_index in others {
+                        let (other_chunk_index, other_triangle, other_a, other_b) =
+                            border_edges[other_index];
+                        // every pair once
+                        if other_index <= edge_index || other_chunk_index == chunk_index {
+                            continue;
+                        }
+                        let matches = (a.distance(other_a) <= tolerance
+                            && b.distance(other_b) <= tolerance)
+                            || (a.distance(other_b) <= tolerance
+                                && b.distance(other_a) <= tolerance);
+                        if matches {
+                            links.push(IsosurfaceNavMeshLink {
+                                chunk: chunks[chunk_index].0,
+                                triangle,
+                                other_chunk: chunks[other_chunk_index].0,
+                                other_triangle,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+    }
+    links
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nav_mesh(vertices: Vec<Vec3>) -> IsosurfaceNavMesh {
+        let triangles = vec![[0, 1, 2]];
+        let neighbours = triangle_neighbours(&triangles);
+        IsosurfaceNavMesh {
+            vertices,
+            triangles,
+            neighbours,
+        }
+    }
+
+    #[test]
+    fn border_edges_of_chunks_are_linked() {
+        // the shared edge is computed by both chunks, with a rounding difference
+        let first = nav_mesh(vec![Vec3::ZERO, Vec3::X, Vec3::new(0.0, 0.0, -1.0)]);
+        let second = nav_mesh(vec![
+            Vec3::X + Vec3::splat(1e-6),
+            Vec3::ZERO,
+            Vec3::new(0.5, 0.0, 1.0),
+        ]);
+        let (first_chunk, second_chunk) = (Entity::from_raw(1), Entity::from_raw(2));
+
+        let links = nav_mesh_links(&[(first_chunk, &first), (second_chunk, &second)], 0.25);
+        assert_eq!(
+            links,
+            vec![IsosurfaceNavMeshLink {
+                chunk: first_chunk,
+                triangle: 0,
+                other_chunk: second_chunk,
+                other_triangle: 0,
+            }]
+        );
+    }
+}
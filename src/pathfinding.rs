@@ -0,0 +1,279 @@
+use std::{cmp::Ordering, collections::BinaryHeap};
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+
+use crate::Isosurface;
+
+// graph of grid cells an agent fits into, for flying and swimming agents. it uses the grid of
+// the isosurface, one node per cell. everything is in local space of the isosurface
+#[derive(Clone)]
+pub struct IsosurfaceFreeSpace {
+    isosurface: Isosurface,
+    agent_radius: f32,
+    // only free cells are stored
+    free_cells: HashSet<IVec3>,
+}
+
+impl IsosurfaceFreeSpace {
+    // cells are free when the field at their center is larger than the radius
+    pub fn new(isosurface: &Isosurface, agent_radius: f32) -> Self {
+        let cells = isosurface.cells().as_ivec3();
+        let mut free_cells = HashSet::new();
+        for z in 0..cells.z {
+            for y in 0..cells.y {
+                for x in 0..cells.x {
+                    let cell = IVec3::new(x, y, z);
+                    if isosurface.field(cell_center(isosurface, cell)) > agent_radius {
+                        free_cells.insert(cell);
+                    }
+                }
+            }
+        }
+        Self {
+            isosurface: isosurface.clone(),
+            agent_radius,
+            free_cells,
+        }
+    }
+
+    pub fn cell_at(&self, point: Vec3) -> Option<IVec3> {
+        let grid_min = self.isosurface.grid_origin - self.isosurface.grid_size / 2.0;
+        let cell = ((point - grid_min) / self.isosurface.cell_size())
+            .floor()
+            .as_ivec3();
+        self.free_cells.contains(&cell).then_some(cell)
+    }
+
+    pub fn is_free(&self, point: Vec3) -> bool {
+        self.cell_at(point).is_some()
+    }
+
+    // the agent can move along the segment without touching the surface. the segment is
+    // marched by the clearance left at every point, the field is a distance bound so between
+    // samples the agent gets closer to the surface than its radius by at most the minimal step
+    pub fn is_segment_free(&self, start: Vec3, end: Vec3) -> bool {
+        let min_step = 1e-2 * self.isosurface.cell_size().min_element();
+        let length = start.distance(end);
+        let direction = (end - start).normalize_or_zero();
+        let mut distance = 0.0;
+        loop {
+            let point = start + direction * distance.min(length);
+            let clearance = self.isosurface.field(point) - self.agent_radius;
+            if clearance <= 0.0 {
+                return false;
+            }
+            if distance >= length {
+                return true;
+            }
+            distance += clearance.max(min_step);
+        }
+    }
+
+    // a* over free cells with 26 neighbours, the path is smoothed by skipping every point
+    // which can be reached directly from an earlier one. start and goal have to be free
+    // and reachable from the center of their cell
+    pub fn find_path(&self, start: Vec3, goal: Vec3) -> Result<Vec<Vec3>, IsosurfacePathError> {
+        let start_cell = self
+            .endpoint_cell(start)
+            .ok_or(IsosurfacePathError::StartBlocked)?;
+        let goal_cell = self
+            .endpoint_cell(goal)
+            .ok_or(IsosurfacePathError::GoalBlocked)?;
+        let cells = self
+            .find_cells_path(start_cell, goal_cell)
+            .ok_or(IsosurfacePathError::NoPath)?;
+
+        let mut points = vec![start];
+        points.extend(
+            cells
+                .iter()
+                .map(|cell| cell_center(&self.isosurface, *cell)),
+        );
+        points.push(goal);
+        points.dedup();
+        Ok(self.smooth_path(points))
+    }
+
+    fn endpoint_cell(&self, point: Vec3) -> Option<IVec3> {
+        let cell = self.cell_at(point)?;
+        (self.isosurface.field(point) > self.agent_radius
+            && self.is_segment_free(point, cell_center(&self.isosurface, cell)))
+        .then_some(cell)
+    }
+
+    fn find_cells_path(&self, start: IVec3, goal: IVec3) -> Option<Vec<IVec3>> {
+        let cell_size = self.isosurface.cell_size();
+        let heuristic = |cell: IVec3| ((goal - cell).as_vec3() * cell_size).length();
+
+        let mut open = BinaryHeap::new();
+        let mut costs = HashMap::new();
+        let mut came_from = HashMap::new();
+        open.push(OpenCell {
+            estimate: heuristic(start),
+            cell: start,
+        });
+        costs.insert(start, 0.0);
+
+        while let Some(OpenCell { cell, estimate }) = open.pop() {
+            if cell == goal {
+                let mut path = vec![goal];
+                let mut current = goal;
+                while let Some(previous) = came_from.get(&current) {
+                    current = *previous;
+                    path.push(current);
+                }
+                path.reverse();
+                return Some(path);
+            }
+            let cost = costs[&cell];
+            // stale entry, the cell was reached cheaper since it was pushed
+            if estimate > cost + heuristic(cell) {
+                continue;
+            }
+            for z in -1..=1 {
+                for y in -1..=1 {
+                    for x in -1..=1 {
+                        let offset = IVec3::new(x, y, z);
+                        let neighbour = cell + offset;
+                        if offset == IVec3::ZERO || !self.free_cells.contains(&neighbour) {
+                            continue;
+                        }
+                        // moving diagonally can clip the surface between free cells
+                        if offset.abs().element_sum() > 1
+                            && !self.is_segment_free(
+                                cell_center(&self.isosurface, cell),
+                                cell_center(&self.isosurface, neighbour),
+                            )
+                        {
+                            continue;
+                        }
+                        let neighbour_cost = cost + (offset.as_vec3() * cell_size).length();
+                        if costs
+                            .get(&neighbour)
+                            .is_some_and(|known_cost| *known_cost <= neighbour_cost)
+                        {
+                            continue;
+                        }
+                        costs.insert(neighbour, neighbour_cost);
+                        came_from.insert(neighbour, cell);
+                        open.push(OpenCell {
+                            estimate: neighbour_cost + heuristic(neighbour),
+                            cell: neighbour,
+                        });
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn smooth_path(&self, points: Vec<Vec3>) -> Vec<Vec3> {
+        let mut smoothed = vec![points[0]];
+        let mut current = 0;
+        while current < points.len() - 1 {
+            // the next point is always reachable, it's a neighbouring cell or an endpoint
+            // checked against the center of its cell
+            let next = (current + 2..points.len())
+                .rev()
+                .find(|next| self.is_segment_free(points[current], points[*next]))
+                .unwrap_or(current + 1);
+            smoothed.push(points[next]);
+            current = next;
+        }
+        smoothed
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IsosurfacePathError {
+    // the agent doesn't fit at the start or can't reach the center of its cell
+    StartBlocked,
+    GoalBlocked,
+    // free cells around start and goal aren't connected
+    NoPath,
+}
+
+impl std::fmt::Display for IsosurfacePathError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::StartBlocked => write!(f, "start of the path is blocked"),
+            Self::GoalBlocked => write!(f, "goal of the path is blocked"),
+            Self::NoPath => write!(f, "goal can't be reached from the start"),
+        }
+    }
+}
+
+impl std::error::Error for IsosurfacePathError {}
+
+fn cell_center(isosurface: &Isosurface, cell: IVec3) -> Vec3 {
+    let grid_min = isosurface.grid_origin - isosurface.grid_size / 2.0;
+    grid_min + (cell.as_vec3() + 0.5) * isosurface.cell_size()
+}
+
+// ordered so the binary heap pops the lowest estimate first
+struct OpenCell {
+    estimate: f32,
+    cell: IVec3,
+}
+
+impl PartialEq for OpenCell {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for OpenCell {}
+
+impl PartialOrd for OpenCell {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OpenCell {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.estimate.total_cmp(&self.estimate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn free_space() -> IsosurfaceFreeSpace {
+        IsosurfaceFreeSpace::new(&Isosurface::default(), 0.5)
+    }
+
+    #[test]
+    fn blocked_endpoints_are_rejected() {
+        let free_space = free_space();
+        // inside of the tube
+        let solid = Vec3::new(3.0, 0.0, 0.0);
+        let open = Vec3::new(0.0, 0.0, 3.5);
+
+        assert_eq!(
+            free_space.find_path(solid, open),
+            Err(IsosurfacePathError::StartBlocked)
+        );
+        assert_eq!(
+            free_space.find_path(open, solid),
+            Err(IsosurfacePathError::GoalBlocked)
+        );
+    }
+
+    #[test]
+    fn path_around_tube_leaves_and_reaches_endpoints() {
+        let free_space = free_space();
+        let start = Vec3::new(3.0, 0.0, 3.5);
+        let goal = Vec3::new(3.0, 0.0, -3.5);
+
+        let path = free_space.find_path(start, goal).unwrap();
+        assert_eq!(path.first(), Some(&start));
+        assert_eq!(path.last(), Some(&goal));
+        assert!(free_space.is_segment_free(path[0], path[1]));
+        assert!(free_space.is_segment_free(path[path.len() - 2], goal));
+    }
+}
@@ -0,0 +1,83 @@
+use bevy::{
+    picking::{
+        backend::{ray::RayMap, HitData, PointerHits},
+        PickSet, PickingBehavior,
+    },
+    prelude::*,
+    render::view::RenderLayers,
+    utils::HashSet,
+};
+
+use crate::{IsosurfaceHandle, IsosurfaceRaycast};
+
+// picking backend for `IsosurfaceHandle` entities. the mesh backend sees only empty meshes
+// of isosurfaces, this one ray marches their fields instead. it needs the picking plugins,
+// so it isn't added by `IsosurfacePlugin`
+pub struct IsosurfacePickingPlugin;
+
+impl Plugin for IsosurfacePickingPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(PreUpdate, isosurface_picking.in_set(PickSet::Backend));
+    }
+}
+
+// visible isosurfaces hit by every ray of active cameras, from the closest one up to the first
+// one which blocks the ones behind, see `PickingBehavior::should_block_lower`
+fn isosurface_picking(
+    ray_map: Res<RayMap>,
+    cameras: Query<(&Camera, Option<&RenderLayers>)>,
+    isosurfaces: Query<
+        (
+            &ViewVisibility,
+            Option<&RenderLayers>,
+            Option<&PickingBehavior>,
+        ),
+        With<IsosurfaceHandle>,
+    >,
+    raycast: IsosurfaceRaycast,
+    mut output: EventWriter<PointerHits>,
+) {
+    let default_layers = RenderLayers::default();
+    for (&ray_id, &ray) in ray_map.map().iter() {
+        let Ok((camera, camera_layers)) = cameras.get(ray_id.camera) else {
+            continue;
+        };
+        if !camera.is_active {
+            continue;
+        }
+        let camera_layers = camera_layers.unwrap_or(&default_layers);
+
+        // every cast finds the next closest isosurface, hit ones are skipped
+        let mut hit_entities = HashSet::new();
+        let mut picks = Vec::new();
+        while let Some(hit) = raycast.cast_ray_filtered(ray, f32::MAX, |entity| {
+            let Ok((visibility, layers, _)) = isosurfaces.get(entity) else {
+                return false;
+            };
+            !hit_entities.contains(&entity)
+                && visibility.get()
+                && camera_layers.intersects(layers.unwrap_or(&default_layers))
+        }) {
+            hit_entities.insert(hit.entity);
+            let hit_data = HitData::new(
+                ray_id.camera,
+                hit.distance,
+                Some(hit.point),
+                Some(*hit.normal),
+            );
+            picks.push((hit.entity, hit_data));
+            let blocks_lower = isosurfaces
+                .get(hit.entity)
+                .ok()
+                .and_then(|(_, _, behavior)| behavior)
+                .is_none_or(|behavior| behavior.should_block_lower);
+            if blocks_lower {
+                break;
+            }
+        }
+        if picks.is_empty() {
+            continue;
+        }
+        output.send(PointerHits::new(ray_id.pointer, picks, camera.order as f32));
+    }
+}
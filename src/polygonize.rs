@@ -0,0 +1,417 @@
+use bevy::{
+    asset::RenderAssetUsages,
+    prelude::*,
+    render::mesh::{Indices, PrimitiveTopology},
+};
+
+use crate::{Isosurface, IsosurfaceNormals};
+
+// corners of a cell, bit 0 is x, bit 1 is y and bit 2 is z
+const CELL_EDGES: [(usize, usize); 12] = [
+    (0, 1),
+    (0, 2),
+    (0, 4),
+    (1, 3),
+    (1, 5),
+    (2, 3),
+    (2, 6),
+    (3, 7),
+    (4, 5),
+    (4, 6),
+    (5, 7),
+    (6, 7),
+];
+
+struct CellVertex {
+    index: u32,
+    // first 3 edges are crossed, the next 3 bits are set if their first corner is outside
+    intersections_bitmask: u32,
+}
+
+struct CellSample {
+    point: Vec3,
+    normal: Vec3,
+    intersections_bitmask: u32,
+}
+
+impl Isosurface {
+    // same vertices and quads as the compute pass produces, but generated on the cpu.
+    // stitching aprons are polygonized the same way, so meshes of neighbouring grids meet.
+    // it's slow, meant for export and physics rather than for every frame
+    pub fn polygonize(&self) -> Mesh {
+        let cells = self.cells();
+        let cell_size = self.cell_size();
+        let grid_min = self.grid_origin - self.grid_size / 2.0;
+        // cells are addressed like `grid_cell` in the shader, negative or >= cells in aprons
+        let apron_min = self.stitching.apron_min().as_ivec3();
+        let extent = (cells + self.stitching.apron_min() + self.stitching.apron_max()).as_ivec3();
+        let offsets: [IVec3; 8] = std::array::from_fn(corner_offset);
+
+        let corners_extent = extent + 1;
+        let corner_index = |corner: IVec3| {
+            let corner = corner + apron_min;
+            (corner.x
+                + corner.y * corners_extent.x
+                + corner.z * corners_extent.x * corners_extent.y) as usize
+        };
+        let mut corner_values = vec![0.0; corners_extent.element_product() as usize];
+        for z in 0..corners_extent.z {
+            for y in 0..corners_extent.y {
+                for x in 0..corners_extent.x {
+                    let corner = IVec3::new(x, y, z) - apron_min;
+                    corner_values[corner_index(corner)] =
+                        self.field(grid_min + corner.as_vec3() * cell_size);
+                }
+            }
+        }
+
+        let cell_index = |cell: IVec3| {
+            let cell = cell + apron_min;
+            (cell.cmpge(IVec3::ZERO).all() && cell.cmplt(extent).all())
+                .then(|| (cell.x + cell.y * extent.x + cell.z * extent.x * extent.y) as usize)
+        };
+        let mut cell_vertices: Vec<Option<CellVertex>> = Vec::new();
+        cell_vertices.resize_with(extent.element_product() as usize, || None);
+        let mut positions = Vec::new();
+        let mut normals = Vec::new();
+
+        for z in 0..extent.z {
+            for y in 0..extent.y {
+                for x in 0..extent.x {
+                    let cell = IVec3::new(x, y, z) - apron_min;
+                    let cell_origin = grid_min + cell.as_vec3() * cell_size;
+                    let values = offsets.map(|offset| corner_values[corner_index(cell + offset)]);
+                    let Some(mut sample) = self.sample_cell(&values, cell_origin, cell_size) else {
+                        continue;
+                    };
+
+                    // apron cells next to a coarser neighbour take the vertex of the
+                    // neighbour's cell, like `find_vertices`
+                    let scale = self.apron_scale(cell);
+                    if scale > 1 {
+                        let coarse_size = cell_size * scale as f32;
+                        let coarse_origin =
+                            grid_min + self.coarse_cell(cell, scale).as_vec3() * cell_size;
+                        let coarse_values = offsets.map(|offset| {
+                            self.field(coarse_origin + offset.as_vec3() * coarse_size)
+                        });
+                        if let Some(coarse) =
+                            self.sample_cell(&coarse_values, coarse_origin, coarse_size)
+                        {
+                            sample.point = coarse.point;
+                            sample.normal = coarse.normal;
+                        }
+                    }
+
+                    if self.normals == IsosurfaceNormals::Gradient {
+                        sample.normal = self.field_gradient(sample.point).normalize_or_zero();
+                    }
+                    cell_vertices[cell_index(cell).unwrap()] = Some(CellVertex {
+                        index: positions.len() as u32,
+                        intersections_bitmask: sample.intersections_bitmask,
+                    });
+                    positions.push(sample.point.to_array());
+                    normals.push(sample.normal.to_array());
+                }
+            }
+        }
+
+        // quads around the edges on the negative corner of every cell, like `connect_vertices`
+        let vertex = |cell: IVec3| {
+            cell_index(cell)
+                .and_then(|index| cell_vertices[index].as_ref())
+                .map(|vertex| vertex.index)
+        };
+        let mut indices = Vec::new();
+        for z in 0..extent.z {
+            for y in 0..extent.y {
+                for x in 0..extent.x {
+                    let cell = IVec3::new(x, y, z) - apron_min;
+                    let Some(cell_vertex) = &cell_vertices[cell_index(cell).unwrap()] else {
+                        continue;
+                    };
+                    for axis in 0..3 {
+                        if cell_vertex.intersections_bitmask & (1 << axis) == 0
+                            || !self.owns_edge(cell, axis)
+                        {
+                            continue;
+                        }
+                        let (first, second) = match axis {
+                            0 => (IVec3::Y, IVec3::Z),
+                            1 => (IVec3::X, IVec3::Z),
+                            _ => (IVec3::X, IVec3::Y),
+                        };
+                        let (Some(point1), Some(point2), Some(point3)) = (
+                            vertex(cell - first),
+                            vertex(cell - second),
+                            vertex(cell - first - second),
+                        ) else {
+                            continue;
+                        };
+                        let outside = cell_vertex.intersections_bitmask & (1 << (axis + 3)) != 0;
+                        // matches the winding of the shader, the middle axis is flipped there
+                        let cw = if axis == 1 { outside } else { !outside };
+                        let point0 = cell_vertex.index;
+                        if cw {
+                            indices.extend([point0, point1, point2, point1, point3, point2]);
+                        } else {
+                            indices.extend([point2, point1, point0, point2, point3, point1]);
+                        }
+                    }
+                }
+            }
+        }
+
+        Mesh::new(
+            PrimitiveTopology::TriangleList,
+            RenderAssetUsages::default(),
+        )
+        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
+        .with_inserted_indices(Indices::U32(indices))
+    }
+
+    // see `sample_cell` in the shader, `None` when the surface doesn't cross the cell
+    fn sample_cell(
+        &self,
+        values: &[f32; 8],
+        cell_origin: Vec3,
+        cell_size: Vec3,
+    ) -> Option<CellSample> {
+        let corner = |index: usize| cell_origin + corner_offset(index).as_vec3() * cell_size;
+        let mut sum = Vec3::ZERO;
+        let mut intersections_count = 0;
+        let mut intersections_bitmask = 0;
+        for (i, &(p0, p1)) in CELL_EDGES.iter().enumerate() {
+            let (sdf0, sdf1) = (values[p0], values[p1]);
+            if (sdf0 > 0.0) == (sdf1 > 0.0) {
+                continue;
+            }
+            let ratio = sdf0 / (sdf0 - sdf1);
+            sum += corner(p0).lerp(corner(p1), ratio);
+            intersections_count += 1;
+            if i < 3 {
+                intersections_bitmask |= 1 << i;
+                if sdf0 > 0.0 {
+                    intersections_bitmask |= 1 << (i + 3);
+                }
+            }
+        }
+        if intersections_count == 0 {
+            return None;
+        }
+
+        Some(CellSample {
+            point: self.project_to_surface(
+                sum / intersections_count as f32,
+                cell_origin,
+                cell_size,
+            ),
+            normal: corner_normal(values),
+            intersections_bitmask,
+        })
+    }
+
+    // which of the 4 edges along the axis the cell is next to, see `IsosurfaceStitching::edges`
+    fn edge_index(&self, cell: IVec3, axis: usize) -> usize {
+        let cells = self.cells().as_ivec3();
+        let (first, second) = ((axis + 1) % 3, (axis + 2) % 3);
+        (cell[first] >= cells[first]) as usize | ((cell[second] >= cells[second]) as usize) << 1
+    }
+
+    // size of the apron cell measured in cells of this grid, see `apron_scale` in the shader
+    fn apron_scale(&self, cell: IVec3) -> u32 {
+        let cells = self.cells().as_ivec3();
+        let scale_min = self.stitching.apron_scale_min();
+        let scale_max = self.stitching.apron_scale_max();
+        let mut scale = 1;
+        let mut outside_axes = 0;
+        let mut inside_axis = 0;
+        for axis in 0..3 {
+            if cell[axis] < 0 {
+                scale = scale.max(scale_min[axis]);
+                outside_axes += 1;
+            } else if cell[axis] >= cells[axis] {
+                scale = scale.max(scale_max[axis]);
+                outside_axes += 1;
+            } else {
+                inside_axis = axis;
+            }
+        }
+        // cells across an edge belong to the diagonal neighbour
+        if outside_axes == 2 {
+            let edge_scale =
+                self.stitching.edge_scales()[inside_axis][self.edge_index(cell, inside_axis)];
+            if edge_scale != 0 {
+                scale = edge_scale;
+            }
+        }
+        scale
+    }
+
+    // first cell of the coarse neighbour's cell the apron cell is in
+    fn coarse_cell(&self, cell: IVec3, scale: u32) -> IVec3 {
+        let cells = self.cells().as_ivec3();
+        let size = scale as i32;
+        IVec3::from_array(std::array::from_fn(|axis| {
+            if cell[axis] < 0 {
+                -size
+            } else if cell[axis] >= cells[axis] {
+                cells[axis]
+            } else {
+                cell[axis] / size * size
+            }
+        }))
+    }
+
+    // quads are generated only for edges owned by this grid, see `owns_edge` in the shader
+    fn owns_edge(&self, cell: IVec3, axis: usize) -> bool {
+        let cells = self.cells().as_ivec3();
+        let apron_min = self.stitching.apron_min();
+        let apron_max = self.stitching.apron_max();
+        let mut boundary_axes = 0;
+        for other in 0..3 {
+            if other == axis {
+                if cell[other] < 0 || cell[other] >= cells[other] {
+                    return false;
+                }
+                continue;
+            }
+            if cell[other] < 0 || cell[other] > cells[other] {
+                return false;
+            }
+            if cell[other] == 0 {
+                if apron_min[other] == 0 {
+                    return false;
+                }
+                boundary_axes += 1;
+            }
+            if cell[other] == cells[other] {
+                if apron_max[other] == 0 {
+                    return false;
+                }
+                boundary_axes += 1;
+            }
+        }
+        if boundary_axes == 2 {
+            return self.stitching.edge_scales()[axis][self.edge_index(cell, axis)] != 0;
+        }
+        true
+    }
+
+    // vertex stays inside of its cell, see `project_to_surface` in the shader
+    fn project_to_surface(&self, point: Vec3, cell_origin: Vec3, cell_size: Vec3) -> Vec3 {
+        let Some(projection) = self.projection else {
+            return point;
+        };
+        let mut projected = point;
+        for _ in 0..projection.iterations {
+            let value = self.field(projected);
+            if value.abs() < projection.tolerance {
+                break;
+            }
+            let gradient = self.field_gradient(projected);
+            let gradient_length_squared = gradient.length_squared();
+            if gradient_length_squared == 0.0 {
+                break;
+            }
+            projected = (projected - gradient * (value / gradient_length_squared))
+                .clamp(cell_origin, cell_origin + cell_size);
+        }
+        projected
+    }
+}
+
+fn corner_offset(corner: usize) -> IVec3 {
+    IVec3::new(
+        corner as i32 & 1,
+        (corner as i32 >> 1) & 1,
+        (corner as i32 >> 2) & 1,
+    )
+}
+
+fn corner_normal(values: &[f32; 8]) -> Vec3 {
+    let dx = (values[1] - values[0])
+        + (values[3] - values[2])
+        + (values[5] - values[4])
+        + (values[7] - values[6]);
+    let dy = (values[2] - values[0])
+        + (values[3] - values[1])
+        + (values[6] - values[4])
+        + (values[7] - values[5]);
+    let dz = (values[4] - values[0])
+        + (values[5] - values[1])
+        + (values[6] - values[2])
+        + (values[7] - values[3]);
+    Vec3::new(dx, dy, dz).normalize_or_zero()
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::render::mesh::VertexAttributeValues;
+
+    use super::*;
+    use crate::IsosurfaceStitching;
+
+    fn positions(mesh: &Mesh) -> Vec<Vec3> {
+        let Some(VertexAttributeValues::Float32x3(positions)) =
+            mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+        else {
+            panic!("polygonized mesh has no positions");
+        };
+        positions.iter().copied().map(Vec3::from_array).collect()
+    }
+
+    // the face at x = 0 cuts the tube, the grid on the positive side stitches it
+    fn assert_apron_meets_neighbour(density: u32, halvings: u32) {
+        let mut stitching = IsosurfaceStitching::default();
+        stitching.negative[0] = Some(halvings);
+        let stitched = Isosurface {
+            grid_size: Vec3::splat(6.0),
+            grid_origin: Vec3::new(3.0, 0.0, 0.0),
+            grid_density: UVec3::splat(density),
+            stitching,
+            ..default()
+        };
+        let neighbour = Isosurface {
+            grid_origin: Vec3::new(-3.0, 0.0, 0.0),
+            grid_density: UVec3::splat(density >> halvings),
+            ..stitched.clone()
+        };
+
+        let mesh = stitched.polygonize();
+        let neighbour_positions = positions(&neighbour.polygonize());
+        let apron_positions: Vec<Vec3> = positions(&mesh)
+            .into_iter()
+            .filter(|position| position.x < 0.0)
+            .collect();
+        assert!(!apron_positions.is_empty());
+        for position in apron_positions {
+            assert!(neighbour_positions
+                .iter()
+                .any(|other| other.distance(position) < 1e-4));
+        }
+    }
+
+    #[test]
+    fn apron_meets_neighbour_of_same_density() {
+        assert_apron_meets_neighbour(1, 0);
+    }
+
+    #[test]
+    fn apron_meets_coarser_neighbour() {
+        assert_apron_meets_neighbour(2, 1);
+    }
+
+    #[test]
+    fn unstitched_faces_have_no_apron() {
+        let isosurface = Isosurface {
+            grid_size: Vec3::splat(6.0),
+            grid_origin: Vec3::new(3.0, 0.0, 0.0),
+            ..default()
+        };
+        assert!(positions(&isosurface.polygonize())
+            .iter()
+            .all(|position| position.x >= 0.0));
+    }
+}
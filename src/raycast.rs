@@ -0,0 +1,119 @@
+use bevy::{ecs::system::SystemParam, prelude::*};
+
+use crate::{Isosurface, IsosurfaceHandle};
+
+// steps of sphere tracing before the ray is considered to miss, grazing rays take the most
+const MAX_TRACE_STEPS: u32 = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IsosurfaceRayHit {
+    pub entity: Entity,
+    // in world space
+    pub point: Vec3,
+    pub normal: Dir3,
+    // from the origin of the ray in world space
+    pub distance: f32,
+}
+
+// rays are traced against the field on the cpu, so meshes of isosurfaces are never needed.
+// only the part of the field inside of the grid box is hit, like only it's polygonized
+#[derive(SystemParam)]
+pub struct IsosurfaceRaycast<'w, 's> {
+    isosurfaces: Res<'w, Assets<Isosurface>>,
+    entities: Query<'w, 's, (Entity, &'static IsosurfaceHandle, &'static GlobalTransform)>,
+}
+
+impl IsosurfaceRaycast<'_, '_> {
+    // closest hit of all isosurface entities
+    pub fn cast_ray(&self, ray: Ray3d, max_distance: f32) -> Option<IsosurfaceRayHit> {
+        self.cast_ray_filtered(ray, max_distance, |_| true)
+    }
+
+    pub fn cast_ray_filtered(
+        &self,
+        ray: Ray3d,
+        max_distance: f32,
+        filter: impl Fn(Entity) -> bool,
+    ) -> Option<IsosurfaceRayHit> {
+        let mut closest: Option<IsosurfaceRayHit> = None;
+        for (entity, isosurface_handle, transform) in self.entities.iter() {
+            if !filter(entity) {
+                continue;
+            }
+            let Some(isosurface) = self.isosurfaces.get(&isosurface_handle.0) else {
+                continue;
+            };
+            let max_distance = closest.map_or(max_distance, |hit| hit.distance);
+            if let Some(hit) = isosurface_ray_hit(entity, isosurface, transform, ray, max_distance)
+            {
+                closest = Some(hit);
+            }
+        }
+        closest
+    }
+}
+
+// the ray is traced in local space, distances are converted back by the scale along the ray
+fn isosurface_ray_hit(
+    entity: Entity,
+    isosurface: &Isosurface,
+    transform: &GlobalTransform,
+    ray: Ray3d,
+    max_distance: f32,
+) -> Option<IsosurfaceRayHit> {
+    let affine = transform.affine();
+    let inverse = affine.inverse();
+    let local_origin = inverse.transform_point3(ray.origin);
+    let local_direction = inverse.transform_vector3(*ray.direction);
+    let local_scale = local_direction.length();
+    if local_scale == 0.0 || !local_scale.is_finite() {
+        return None;
+    }
+
+    let local_distance = isosurface.sphere_trace(
+        local_origin,
+        local_direction / local_scale,
+        max_distance * local_scale,
+    )?;
+    let local_point = local_origin + local_direction / local_scale * local_distance;
+    let local_normal = isosurface.field_gradient(local_point);
+    // normals are transformed by the inverse transpose to stay perpendicular under scale
+    let normal = Dir3::new(inverse.matrix3.transpose() * local_normal).ok()?;
+    Some(IsosurfaceRayHit {
+        entity,
+        point: affine.transform_point3(local_point),
+        normal,
+        distance: local_distance / local_scale,
+    })
+}
+
+impl Isosurface {
+    // distance along the normalized direction in local space to the first point where the
+    // field is negative. the grid box is entered first, everything outside of it is skipped
+    pub fn sphere_trace(&self, origin: Vec3, direction: Vec3, max_distance: f32) -> Option<f32> {
+        let aabb = self.grid_aabb();
+        let (min, max) = (Vec3::from(aabb.min()), Vec3::from(aabb.max()));
+        let inverse_direction = direction.recip();
+        let t0 = (min - origin) * inverse_direction;
+        let t1 = (max - origin) * inverse_direction;
+        let enter = t0.min(t1).max_element().max(0.0);
+        let exit = t0.max(t1).min_element().min(max_distance);
+        if enter > exit {
+            return None;
+        }
+
+        let epsilon = 1e-3 * self.cell_size().min_element();
+        let mut distance = enter;
+        for _ in 0..MAX_TRACE_STEPS {
+            let value = self.field(origin + direction * distance);
+            if value < epsilon {
+                return Some(distance);
+            }
+            distance += value;
+            if distance > exit {
+                return None;
+            }
+        }
+        None
+    }
+}
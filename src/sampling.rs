@@ -0,0 +1,234 @@
+use bevy::{prelude::*, render::mesh::VertexAttributeValues, utils::HashMap};
+
+use crate::{field::sdf_material, Isosurface};
+
+// newton steps moving samples from triangles onto the field
+const PROJECTION_ITERATIONS: u32 = 4;
+// candidates thrown per disk of poisson sampling
+const POISSON_CANDIDATES: f32 = 8.0;
+// the density is lowered for large surfaces so the amount of candidates stays bounded
+const MAX_CANDIDATES: f32 = 1_048_576.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Reflect)]
+pub enum IsosurfaceSampleDistribution {
+    // no two points are closer than the distance
+    Poisson {
+        min_distance: f32,
+    },
+    // random points, on average this many per unit of area. a point is kept with
+    // the probability mixed from `layer_weights` by the normalized weights of `sdf_material`
+    // at the point, `Vec4::ONE` keeps all of them and a layer weighted 0 gets none
+    Density {
+        points_per_area: f32,
+        layer_weights: Vec4,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Reflect)]
+pub struct IsosurfaceSampling {
+    pub distribution: IsosurfaceSampleDistribution,
+    pub up: Dir3,
+    // points on steeper slopes are dropped, in radians
+    pub max_slope: Option<f32>,
+    // the same seed gives the same points for the same isosurface
+    pub seed: u64,
+}
+
+impl Default for IsosurfaceSampling {
+    fn default() -> Self {
+        Self {
+            distribution: IsosurfaceSampleDistribution::Poisson { min_distance: 1.0 },
+            up: Dir3::Y,
+            max_slope: None,
+            seed: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IsosurfaceSurfacePoint {
+    pub position: Vec3,
+    pub normal: Dir3,
+    // normalized weights of `sdf_material`, only with `Isosurface::materials`
+    pub material_weights: Option<Vec4>,
+}
+
+impl Isosurface {
+    // points on the surface in local space of the isosurface. candidates are spread over
+    // triangles of the cpu polygonization by area and moved onto the field.
+    // no points for a distance or a density which isn't positive
+    pub fn sample_surface(&self, sampling: &IsosurfaceSampling) -> Vec<IsosurfaceSurfacePoint> {
+        let mut rng = SplitMix64(sampling.seed);
+        let points_per_area = match sampling.distribution {
+            IsosurfaceSampleDistribution::Poisson { min_distance } if min_distance > 0.0 => {
+                POISSON_CANDIDATES / (std::f32::consts::PI * min_distance * min_distance)
+            }
+            IsosurfaceSampleDistribution::Density {
+                points_per_area, ..
+            } if points_per_area > 0.0 => points_per_area,
+            _ => return Vec::new(),
+        };
+        if !points_per_area.is_finite() {
+            return Vec::new();
+        }
+
+        let min_up = sampling.max_slope.map(f32::cos);
+        let mut points: Vec<IsosurfaceSurfacePoint> = self
+            .triangle_candidates(points_per_area, &mut rng)
+            .into_iter()
+            .filter_map(|candidate| self.surface_point(candidate))
+            .filter(|point| min_up.is_none_or(|min_up| point.normal.dot(*sampling.up) >= min_up))
+            .collect();
+
+        if let IsosurfaceSampleDistribution::Density { layer_weights, .. } = sampling.distribution {
+            points.retain(|point| {
+                let acceptance = material_weights(point.position)
+                    .dot(layer_weights)
+                    .clamp(0.0, 1.0);
+                rng.next_f32() < acceptance
+            });
+        }
+
+        if let IsosurfaceSampleDistribution::Poisson { min_distance } = sampling.distribution {
+            // dart throwing in random order, every accepted point blocks a disk around it
+            for i in (1..points.len()).rev() {
+                points.swap(i, rng.next_index(i + 1));
+            }
+            points = poisson_filter(points, min_distance);
+        }
+        points
+    }
+
+    // every triangle gets its area times the density of points, the fraction is random
+    fn triangle_candidates(&self, points_per_area: f32, rng: &mut SplitMix64) -> Vec<Vec3> {
+        let mesh = self.polygonize();
+        let (Some(VertexAttributeValues::Float32x3(positions)), Some(indices)) =
+            (mesh.attribute(Mesh::ATTRIBUTE_POSITION), mesh.indices())
+        else {
+            return Vec::new();
+        };
+        let indices: Vec<usize> = indices.iter().collect();
+        let triangles: Vec<[Vec3; 3]> = indices
+            .chunks_exact(3)
+            .map(|triangle| [0, 1, 2].map(|i| Vec3::from_array(positions[triangle[i]])))
+            .collect();
+        let area_of = |[a, b, c]: [Vec3; 3]| (b - a).cross(c - a).length() / 2.0;
+        let total_area: f32 = triangles.iter().map(|triangle| area_of(*triangle)).sum();
+        let points_per_area = points_per_area.min(MAX_CANDIDATES / total_area.max(f32::EPSILON));
+
+        let mut candidates = Vec::new();
+        for [a, b, c] in triangles {
+            let area = area_of([a, b, c]);
+            let expected = area * points_per_area;
+            let count = expected as u32 + (rng.next_f32() < expected.fract()) as u32;
+            for _ in 0..count {
+                // uniform barycentric coordinates, folded back into the triangle
+                let (mut u, mut v) = (rng.next_f32(), rng.next_f32());
+                if u + v > 1.0 {
+                    (u, v) = (1.0 - u, 1.0 - v);
+                }
+                candidates.push(a + (b - a) * u + (c - a) * v);
+            }
+        }
+        candidates
+    }
+
+    fn surface_point(&self, candidate: Vec3) -> Option<IsosurfaceSurfacePoint> {
+        let mut position = candidate;
+        for _ in 0..PROJECTION_ITERATIONS {
+            let gradient = self.field_gradient(position);
+            let gradient_length_squared = gradient.length_squared();
+            if gradient_length_squared == 0.0 {
+                break;
+            }
+            position -= gradient * (self.field(position) / gradient_length_squared);
+        }
+        let normal = Dir3::new(self.field_gradient(position)).ok()?;
+        Some(IsosurfaceSurfacePoint {
+            position,
+            normal,
+            material_weights: self.materials.then(|| material_weights(position)),
+        })
+    }
+}
+
+// normalized like the vertex colors of the compute pass
+fn material_weights(position: Vec3) -> Vec4 {
+    let weights = sdf_material(position).max(Vec4::ZERO);
+    weights / weights.element_sum().max(1e-6)
+}
+
+// spatial hash with cells of the min distance, so only neighbouring cells are checked
+fn poisson_filter(
+    points: Vec<IsosurfaceSurfacePoint>,
+    min_distance: f32,
+) -> Vec<IsosurfaceSurfacePoint> {
+    let cell_of = |position: Vec3| (position / min_distance).floor().as_ivec3();
+    let mut grid: HashMap<IVec3, Vec<Vec3>> = HashMap::new();
+    let mut accepted = Vec::new();
+    for point in points {
+        let cell = cell_of(point.position);
+        let blocked = (-1..=1).any(|z| {
+            (-1..=1).any(|y| {
+                (-1..=1).any(|x| {
+                    grid.get(&(cell + IVec3::new(x, y, z)))
+                        .is_some_and(|positions| {
+                            positions.iter().any(|position| {
+                                position.distance_squared(point.position)
+                                    < min_distance * min_distance
+                            })
+                        })
+                })
+            })
+        });
+        if blocked {
+            continue;
+        }
+        grid.entry(cell).or_default().push(point.position);
+        accepted.push(point);
+    }
+    accepted
+}
+
+// small deterministic generator, sampling doesn't need anything better
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // in [0, 1)
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    fn next_index(&mut self, len: usize) -> usize {
+        (self.next_u64() % len as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_weight_layer_gets_no_samples() {
+        // grass is the only layer above z = 1.5, the top of the tube
+        let sampling = IsosurfaceSampling {
+            distribution: IsosurfaceSampleDistribution::Density {
+                points_per_area: 4.0,
+                layer_weights: Vec4::new(1.0, 1.0, 0.0, 1.0),
+            },
+            ..default()
+        };
+        let points = Isosurface::default().sample_surface(&sampling);
+
+        assert!(!points.is_empty());
+        assert!(points.iter().all(|point| point.position.z < 1.5));
+    }
+}
@@ -0,0 +1,271 @@
+use bevy::{prelude::*, utils::HashMap};
+
+use crate::{Isosurface, IsosurfaceHandle, IsosurfaceMaterial, IsosurfaceStitching};
+
+pub struct IsosurfaceVolumePlugin;
+
+impl Plugin for IsosurfaceVolumePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            PostUpdate,
+            (
+                update_volume_chunks.before(TransformSystem::TransformPropagate),
+                copy_volume_component::<MeshMaterial3d<StandardMaterial>>
+                    .after(update_volume_chunks),
+                copy_volume_component::<MeshMaterial3d<IsosurfaceMaterial>>
+                    .after(update_volume_chunks),
+            ),
+        )
+        .register_type::<IsosurfaceVolume>()
+        .register_type::<IsosurfaceVolumeFocus>()
+        .register_type::<IsosurfaceChunk>();
+    }
+}
+
+// unbounded isosurface split into chunks. every chunk is a child entity with its own
+// `Isosurface` covering one cell region of the field, chunks are spawned around
+// the `IsosurfaceVolumeFocus` entity and despawned once it moves away.
+// `MeshMaterial3d<StandardMaterial>` and `MeshMaterial3d<IsosurfaceMaterial>` of the volume
+// are copied to its chunks, also when they change later.
+//
+// far chunks use coarser grids, every lod level halves `chunk_density`, so it should be
+// a power of two. chunks are stitched to their neighbours without cracks between levels
+#[derive(Component, Clone, Debug, Reflect)]
+#[reflect(Component, Default)]
+#[require(Transform, Visibility, VolumeChunks)]
+pub struct IsosurfaceVolume {
+    // size of one chunk in local space of the volume
+    pub chunk_size: Vec3,
+    // `Isosurface::grid_density` of every chunk
+    pub chunk_density: UVec3,
+    // chunks within this amount of chunks from the focus are spawned
+    pub load_radius: u32,
+    // chunks are despawned only when they are further than this amount of chunks,
+    // so moving back and forth across a chunk border doesn't respawn them every frame
+    pub unload_radius: u32,
+    // amount of coarser levels, 0 disables lod
+    pub lod_levels: u32,
+    // amount of chunks from the focus covered by every lod level
+    pub lod_step: u32,
+    // every chunk is a copy of this isosurface, so uvs, normals, ambient occlusion,
+    // materials and the rest are set here. grid box, `grid_density` and `stitching`
+    // are overwritten per chunk, `IsosurfaceBoundary::Capped` would cap stitched faces too
+    pub chunk_template: Isosurface,
+}
+
+impl Default for IsosurfaceVolume {
+    fn default() -> Self {
+        Self {
+            chunk_size: Vec3::splat(10.0),
+            chunk_density: UVec3::splat(1),
+            load_radius: 2,
+            unload_radius: 3,
+            lod_levels: 0,
+            lod_step: 2,
+            chunk_template: Isosurface::default(),
+        }
+    }
+}
+
+impl IsosurfaceVolume {
+    pub fn chunk_at(&self, local_position: Vec3) -> IVec3 {
+        (local_position / self.chunk_size).floor().as_ivec3()
+    }
+
+    // grid density can't be halved below 1
+    pub fn max_lod(&self) -> u32 {
+        self.lod_levels
+            .min(self.chunk_density.min_element().max(1).ilog2())
+    }
+
+    pub fn chunk_lod(&self, coordinates: IVec3, center: IVec3) -> u32 {
+        let distance = (coordinates - center).abs().max_element() as u32;
+        (distance / self.lod_step.max(1)).min(self.max_lod())
+    }
+
+    // grid box of the chunk, neighbouring chunks share their faces. the face between chunks
+    // of the same lod is stitched by the chunk on the positive side, otherwise by the finer one.
+    // edges shared by four chunks are stitched by the finest of them, see `edge_owner`
+    pub fn chunk_isosurface(&self, coordinates: IVec3, center: IVec3) -> Isosurface {
+        let lod = self.chunk_lod(coordinates, center);
+        let mut stitching = IsosurfaceStitching::default();
+        for axis in 0..3 {
+            let step = IVec3::AXES[axis];
+            let negative_lod = self.chunk_lod(coordinates - step, center);
+            if negative_lod >= lod {
+                stitching.negative[axis] = Some(negative_lod - lod);
+            }
+            let positive_lod = self.chunk_lod(coordinates + step, center);
+            if positive_lod > lod {
+                stitching.positive[axis] = Some(positive_lod - lod);
+            }
+        }
+        for axis in 0..3 {
+            let first = IVec3::AXES[(axis + 1) % 3];
+            let second = IVec3::AXES[(axis + 2) % 3];
+            for index in 0..4 {
+                let first_step = if index & 1 != 0 { first } else { -first };
+                let second_step = if index & 2 != 0 { second } else { -second };
+                let diagonal = coordinates + first_step + second_step;
+                let chunks = [
+                    coordinates,
+                    coordinates + first_step,
+                    coordinates + second_step,
+                    diagonal,
+                ];
+                if self.edge_owner(chunks, axis, center) == coordinates {
+                    stitching.edges[axis][index] = Some(self.chunk_lod(diagonal, center) - lod);
+                }
+            }
+        }
+        Isosurface {
+            grid_size: self.chunk_size,
+            grid_origin: (coordinates.as_vec3() + 0.5) * self.chunk_size,
+            grid_density: self.chunk_density >> lod,
+            stitching,
+            ..self.chunk_template.clone()
+        }
+    }
+
+    // the finest of the four chunks around an edge along the axis, of equal ones the most
+    // positive. it's finer than or on the positive side of both its face neighbours
+    // around the edge, so it stitches both faces the edge is on
+    fn edge_owner(&self, chunks: [IVec3; 4], axis: usize, center: IVec3) -> IVec3 {
+        let first = (axis + 1) % 3;
+        let second = (axis + 2) % 3;
+        chunks
+            .into_iter()
+            .min_by_key(|chunk| {
+                (
+                    self.chunk_lod(*chunk, center),
+                    -(chunk[first] + chunk[second]),
+                    -chunk[first],
+                )
+            })
+            .unwrap()
+    }
+}
+
+// chunks are spawned around the first entity with this component, usually the camera
+#[derive(Component, Clone, Copy, Debug, Default, Reflect)]
+#[reflect(Component, Default)]
+pub struct IsosurfaceVolumeFocus;
+
+#[derive(Component, Clone, Copy, Debug, Reflect, PartialEq, Eq)]
+#[reflect(Component)]
+pub struct IsosurfaceChunk {
+    pub volume: Entity,
+    pub coordinates: IVec3,
+}
+
+struct VolumeChunk {
+    entity: Entity,
+    isosurface: Handle<Isosurface>,
+}
+
+#[derive(Component, Default)]
+struct VolumeChunks {
+    chunks: HashMap<IVec3, VolumeChunk>,
+    // chunk the focus was in last time, lod and stitching depend only on it
+    center: Option<IVec3>,
+}
+
+pub(crate) fn update_volume_chunks(
+    mut commands: Commands,
+    mut isosurfaces: ResMut<Assets<Isosurface>>,
+    focus: Query<&GlobalTransform, With<IsosurfaceVolumeFocus>>,
+    mut volumes: Query<(
+        Entity,
+        Ref<IsosurfaceVolume>,
+        &GlobalTransform,
+        &mut VolumeChunks,
+    )>,
+) {
+    let Some(focus_transform) = focus.iter().next() else {
+        return;
+    };
+
+    for (volume_entity, volume, volume_transform, mut chunks) in volumes.iter_mut() {
+        let local_focus = volume_transform
+            .affine()
+            .inverse()
+            .transform_point3(focus_transform.translation());
+        let center = volume.chunk_at(local_focus);
+
+        if chunks.center == Some(center) && !volume.is_changed() {
+            continue;
+        }
+        chunks.center = Some(center);
+
+        let unload_radius = volume.unload_radius.max(volume.load_radius) as i32;
+        chunks.chunks.retain(|coordinates, chunk| {
+            let keep = (*coordinates - center).abs().max_element() <= unload_radius;
+            if !keep {
+                commands.entity(chunk.entity).despawn_recursive();
+            }
+            keep
+        });
+
+        // lod of chunks depends on the focus and the volume itself may have changed,
+        // kept chunks are regenerated only when their isosurface is different now
+        for (coordinates, chunk) in chunks.chunks.iter() {
+            let new_isosurface = volume.chunk_isosurface(*coordinates, center);
+            if isosurfaces.get(&chunk.isosurface) != Some(&new_isosurface) {
+                isosurfaces.insert(&chunk.isosurface, new_isosurface);
+            }
+        }
+
+        let load_radius = volume.load_radius as i32;
+        for z in -load_radius..=load_radius {
+            for y in -load_radius..=load_radius {
+                for x in -load_radius..=load_radius {
+                    let coordinates = center + IVec3::new(x, y, z);
+                    if chunks.chunks.contains_key(&coordinates) {
+                        continue;
+                    }
+                    let isosurface = isosurfaces.add(volume.chunk_isosurface(coordinates, center));
+                    let chunk_entity = commands
+                        .spawn((
+                            IsosurfaceHandle(isosurface.clone()),
+                            IsosurfaceChunk {
+                                volume: volume_entity,
+                                coordinates,
+                            },
+                        ))
+                        .id();
+                    commands.entity(volume_entity).add_child(chunk_entity);
+                    chunks.chunks.insert(
+                        coordinates,
+                        VolumeChunk {
+                            entity: chunk_entity,
+                            isosurface,
+                        },
+                    );
+                }
+            }
+        }
+    }
+}
+
+// keeps a component of the volume on all of its chunks, spawned chunks get it the same frame
+// and existing ones whenever it changes. it's removed from chunks once the volume lost it
+pub(crate) fn copy_volume_component<C: Component + Clone>(
+    mut commands: Commands,
+    volumes: Query<Option<Ref<C>>, With<IsosurfaceVolume>>,
+    chunks: Query<(Entity, &IsosurfaceChunk, Has<C>)>,
+) {
+    for (chunk_entity, chunk, chunk_has_component) in chunks.iter() {
+        let Ok(component) = volumes.get(chunk.volume) else {
+            continue;
+        };
+        match component {
+            Some(component) if component.is_changed() || !chunk_has_component => {
+                commands.entity(chunk_entity).insert(C::clone(&component));
+            }
+            None if chunk_has_component => {
+                commands.entity(chunk_entity).remove::<C>();
+            }
+            _ => {}
+        }
+    }
+}